@@ -0,0 +1,177 @@
+//! Bound arguments that can be logged.
+//!
+//! sqlx doesn't let `MySqlArguments` be read back once a value is bound, so `LoggedArgs` keeps
+//! a log line of each value next to the binding, while the value is still typed. Every value
+//! goes through the masker of `set_arg_masker` first, so personal data such as emails or
+//! national ids never reaches the logs, as GDPR / PCI style policies require:
+//!
+//! ```ignore
+//! args::set_arg_masker(Box::new(|name, _| match name {
+//!     "email" | "ssn" => args::MaskedValue::Redact,
+//!     _ => args::MaskedValue::Keep,
+//! }));
+//!
+//! let mut args = args::LoggedArgs::new();
+//! args.add("email", email).add("age", 30);
+//! tracing::debug!("find user, {}", args); // email=***, age=30
+//! let users = mysql::query_as_with::<User, _>(&mut conn, sql, args.into_arguments()).await?;
+//! ```
+
+use std::fmt;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use sqlx::{encode::Encode, mysql::MySqlArguments, Arguments, MySql, Type};
+
+
+/// A bound value as the masker sees it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Text(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Bool(a) => write!(f, "{}", a),
+            Value::Int(a) => write!(f, "{}", a),
+            Value::UInt(a) => write!(f, "{}", a),
+            Value::Float(a) => write!(f, "{}", a),
+            Value::Text(a) => write!(f, "{:?}", crate::sql::truncate_for_log(a)),
+            Value::Bytes(a) => write!(f, "<{} bytes>", a.len()),
+        }
+    }
+}
+
+/// What the masker logs for a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaskedValue {
+    /// the value itself, the default
+    Keep,
+    /// `***`
+    Redact,
+    /// this text instead of the value, e.g. the last 4 digits of a card number
+    Replace(String),
+}
+
+type Masker = Box<dyn Fn(&str, &Value) -> MaskedValue + Send + Sync>;
+
+static ARG_MASKER: Lazy<RwLock<Option<Masker>>> = Lazy::new(|| RwLock::new(None));
+
+
+/// Sets the masker applied to every value before `LoggedArgs` logs it, called with the name
+/// the value was added under. Without one, values are logged as they are.
+pub fn set_arg_masker(masker: Masker) {
+    *ARG_MASKER.write().unwrap() = Some(masker);
+}
+
+/// A value `LoggedArgs` can bind and log.
+pub trait LogValue {
+    fn log_value(&self) -> Value<'_>;
+}
+
+macro_rules! _log_value {
+    ($variant:ident as $as:ty: $($ty:ty),*) => {
+        $(
+            impl LogValue for $ty {
+                fn log_value(&self) -> Value<'_> {
+                    Value::$variant(*self as $as)
+                }
+            }
+        )*
+    };
+}
+_log_value!(Int as i64: i8, i16, i32, i64);
+_log_value!(UInt as u64: u8, u16, u32, u64);
+_log_value!(Float as f64: f32, f64);
+
+impl LogValue for bool {
+    fn log_value(&self) -> Value<'_> {
+        Value::Bool(*self)
+    }
+}
+
+impl LogValue for &str {
+    fn log_value(&self) -> Value<'_> {
+        Value::Text(self)
+    }
+}
+
+impl LogValue for String {
+    fn log_value(&self) -> Value<'_> {
+        Value::Text(self)
+    }
+}
+
+impl LogValue for Vec<u8> {
+    fn log_value(&self) -> Value<'_> {
+        Value::Bytes(self)
+    }
+}
+
+impl LogValue for &[u8] {
+    fn log_value(&self) -> Value<'_> {
+        Value::Bytes(self)
+    }
+}
+
+impl<T: LogValue> LogValue for Option<T> {
+    fn log_value(&self) -> Value<'_> {
+        match self {
+            Some(a) => a.log_value(),
+            None => Value::Null,
+        }
+    }
+}
+
+/// `MySqlArguments` with a masked log line of every value, shown by its `Display`.
+#[derive(Default)]
+pub struct LoggedArgs {
+    arguments: MySqlArguments,
+    log: Vec<String>,
+}
+
+impl LoggedArgs {
+    pub fn new() -> Self {
+        LoggedArgs::default()
+    }
+
+    /// Binds `value` as the next `?`, `name` is what the log line and the masker call it.
+    pub fn add<'q, T>(&mut self, name: &str, value: T) -> &mut Self
+    where
+        T: LogValue + Encode<'q, MySql> + Type<MySql> + Send + 'q,
+    {
+        let logged = {
+            let value = value.log_value();
+            let masked = match ARG_MASKER.read().unwrap().as_ref() {
+                Some(masker) => masker(name, &value),
+                None => MaskedValue::Keep,
+            };
+            match masked {
+                MaskedValue::Keep => format!("{}={}", name, value),
+                MaskedValue::Redact => format!("{}=***", name),
+                MaskedValue::Replace(text) => format!("{}={}", name, text),
+            }
+        };
+        self.log.push(logged);
+        Arguments::<'q>::add(&mut self.arguments, value);
+        self
+    }
+
+    pub fn into_arguments(self) -> MySqlArguments {
+        self.arguments
+    }
+}
+
+impl fmt::Display for LoggedArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.log.join(", "))
+    }
+}
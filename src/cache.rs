@@ -0,0 +1,88 @@
+use once_cell::sync::Lazy;
+use sqlx::{mysql::{MySqlArguments, MySqlRow}, query::QueryAs, FromRow, MySql, MySqlConnection};
+use std::{any::Any, collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Mutex;
+
+use crate::error::Result;
+
+use crate::mysql;
+
+
+struct Entry {
+    value: Arc<dyn Any + Send + Sync>,
+    // None for a ttl too large to add to now, which never expires
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|a| a <= now)
+    }
+}
+
+// created on first use, so the cache costs nothing unless exec_arr_cached is called
+static CACHE: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+
+/// `mysql::exec_arr` with an in-memory result cache.
+///
+/// `MySqlArguments` can't be hashed, so `key` must identify the database, the sql and its
+/// arguments, e.g. `"DEFAULT:settings:all"`. A cached result is served until `ttl` passes even
+/// if the table changes meanwhile, call `invalidate` after writes that must be seen at once.
+/// A `ttl` like `Duration::MAX` never expires.
+///
+/// Each miss also drops the expired entries of other keys, so keys that aren't read again
+/// don't pile up. That walks the whole cache, which is meant for a few lookup tables, not for
+/// one key per user or request.
+pub async fn exec_arr_cached<'q, T>(conn: &mut MySqlConnection, sql_as: QueryAs<'q, MySql, T, MySqlArguments>, key: &str, ttl: Duration) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Sync + Unpin + Clone + 'static,
+{
+    if let Some(arr) = _get::<Vec<T>>(key).await {
+        return Ok(arr);
+    }
+    let arr = mysql::exec_arr(conn, sql_as).await?;
+    let now = Instant::now();
+    let mut map = CACHE.lock().await;
+    map.retain(|_, a| !a.is_expired(now));
+    map.insert(key.to_string(), Entry {
+        value: Arc::new(arr.clone()),
+        expires_at: now.checked_add(ttl),
+    });
+    Ok(arr)
+}
+
+pub async fn invalidate(key: &str) {
+    let mut map = CACHE.lock().await;
+    map.remove(key);
+}
+
+pub async fn clear_cache() {
+    let mut map = CACHE.lock().await;
+    map.clear();
+}
+
+async fn _get<T: Clone + 'static>(key: &str) -> Option<T> {
+    let mut map = CACHE.lock().await;
+    let entry = map.get(key)?;
+    if entry.is_expired(Instant::now()) {
+        map.remove(key);
+        return None;
+    }
+    entry.value.downcast_ref::<T>().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_ttl_never_expires() {
+        let now = Instant::now();
+        let entry = Entry { value: Arc::new(()), expires_at: now.checked_add(Duration::MAX) };
+        assert!(!entry.is_expired(now + Duration::from_secs(86400 * 365 * 100)));
+        let entry = Entry { value: Arc::new(()), expires_at: now.checked_add(Duration::from_secs(1)) };
+        assert!(!entry.is_expired(now));
+        assert!(entry.is_expired(now + Duration::from_secs(1)));
+    }
+}
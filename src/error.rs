@@ -0,0 +1,359 @@
+use std::fmt;
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+
+use sqlx::mysql::MySqlDatabaseError;
+
+use qx_rs_server::err::Error;
+
+/// What the functions of this crate return, see `DbError`.
+pub type Result<T> = std::result::Result<T, DbError>;
+
+/// The backend an error came from, prefixed to every database error message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DatabaseKind {
+    MySql,
+}
+
+impl fmt::Display for DatabaseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseKind::MySql => write!(f, "mysql"),
+        }
+    }
+}
+
+/// The kinds of failure callers branch on, see `DbError::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// a query that must return a row returned none
+    NotFound,
+    /// no connection became free within the acquire timeout
+    PoolExhausted,
+    /// refused by the circuit breaker, see `pool::circuit_state`
+    CircuitOpen,
+    /// a low priority acquire shed under connection pressure, see `pool::get_conn_priority`
+    Overloaded,
+    /// an acquire on an exhausted pool from a task holding a transaction on it, see
+    /// `MYSQL.SELF_DEADLOCK`
+    PotentialSelfDeadlock,
+    /// missing or invalid configuration
+    Config,
+    /// deadlock (1213), the transaction has been rolled back and can be run again
+    Deadlock,
+    /// a statement or value above the server `max_allowed_packet` (1153), also the
+    /// client side check of `mysql::check_packet_len`
+    PacketTooLarge,
+    Other,
+}
+
+impl ErrorKind {
+    fn of_sqlx(err: &sqlx::Error) -> ErrorKind {
+        match err {
+            sqlx::Error::RowNotFound => ErrorKind::NotFound,
+            sqlx::Error::PoolTimedOut => ErrorKind::PoolExhausted,
+            sqlx::Error::Configuration(source) if !source.is::<WriteOnReadOnly>() => ErrorKind::Config,
+            _ => mysql_errno(err).map_or(ErrorKind::Other, ErrorKind::_of_errno),
+        }
+    }
+
+    fn _of_errno(errno: u16) -> ErrorKind {
+        match errno {
+            1213 => ErrorKind::Deadlock,
+            1153 => ErrorKind::PacketTooLarge,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// The error of every fallible function of this crate: its kind, a message, the sqlx error it
+/// came from as `std::error::Error::source()` and, with the `backtrace` feature, where it was built.
+///
+/// The kind is set where the error is built, from the sqlx error and the server error number
+/// for a failed statement, never read back from the message, which can quote sql and values.
+///
+/// Converts into `Error::Database`, or `Error::Env` for `Config`, with the message, so it
+/// can be returned with `?` where the qx `Result` is used. The kind, source and backtrace are
+/// dropped then, branch on `kind()` before. A qx `Error` converts back as `Config` for
+/// `Error::Env` and `Other` for everything else.
+#[derive(Debug)]
+pub struct DbError {
+    kind: ErrorKind,
+    message: String,
+    source: Option<sqlx::Error>,
+    #[cfg(feature = "backtrace")]
+    backtrace: Backtrace,
+}
+
+impl DbError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        DbError {
+            kind,
+            message: message.into(),
+            source: None,
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::force_capture(),
+        }
+    }
+
+    /// `err` with the `"<kind> <context> failed: <summary>"` message of the helpers.
+    pub fn from_sqlx(kind: DatabaseKind, context: &str, err: sqlx::Error) -> Self {
+        let message = format!("{} {} failed: {}", kind, context, summary(&err));
+        DbError::_from_sqlx(message, err)
+    }
+
+    fn _from_sqlx(message: String, err: sqlx::Error) -> Self {
+        DbError {
+            kind: ErrorKind::of_sqlx(&err),
+            message,
+            source: Some(err),
+            #[cfg(feature = "backtrace")]
+            backtrace: Backtrace::force_capture(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Where the error was built, captured with the `backtrace` feature.
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e as _)
+    }
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(err: sqlx::Error) -> Self {
+        DbError::from_sqlx(DatabaseKind::MySql, "query", err)
+    }
+}
+
+impl From<Error> for DbError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Env(msg) => DbError::new(ErrorKind::Config, msg),
+            Error::Database(msg) => DbError::new(ErrorKind::Other, msg),
+            err => DbError::new(ErrorKind::Other, err.to_string()),
+        }
+    }
+}
+
+impl From<DbError> for Error {
+    fn from(err: DbError) -> Self {
+        match err.kind {
+            ErrorKind::Config => Error::Env(err.message),
+            _ => Error::Database(err.message),
+        }
+    }
+}
+
+// the sqlx error source of a statement rejected by `read_only::ReadOnlyConn`
+#[derive(Debug)]
+pub(crate) struct WriteOnReadOnly;
+
+impl fmt::Display for WriteOnReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "write attempted on read-only connection")
+    }
+}
+
+impl std::error::Error for WriteOnReadOnly {}
+
+/// Single-line summary of a sqlx error, e.g. `ERROR 1062 (23000): Duplicate entry '1' for key 'PRIMARY'`.
+///
+/// Server errors are formatted like the mysql client does, with the error number inline,
+/// decode errors as `failed to decode column 'created_at': mismatched types; Rust type ...`,
+/// a column the `FromRow` struct expects but the query didn't select as
+/// `query result is missing column 'created_at'`,
+/// a packet above `max_allowed_packet` (1153) with what to do about it,
+/// everything else uses the sqlx message with line breaks folded.
+pub fn summary(err: &sqlx::Error) -> String {
+    if let sqlx::Error::PoolClosed = err {
+        return "pool is shutting down".to_string();
+    }
+    if let sqlx::Error::Configuration(source) = err {
+        if source.is::<WriteOnReadOnly>() {
+            return source.to_string();
+        }
+    }
+    if let sqlx::Error::ColumnNotFound(column) = err {
+        return format!("query result is missing column '{}'", column);
+    }
+    if let sqlx::Error::ColumnDecode { index, source } = err {
+        // index is the `{:?}` of the column name or position, the source names the Rust and SQL types
+        return format!("failed to decode column '{}': {}", index.trim_matches('"'), source.to_string().replace('\n', " "));
+    }
+    if let Some(db_err) = err.as_database_error() {
+        if let Some(mysql_err) = db_err.try_downcast_ref::<MySqlDatabaseError>() {
+            return _server_summary(mysql_err.number(), mysql_err.code(), mysql_err.message());
+        }
+    }
+    err.to_string().replace('\n', " ")
+}
+
+fn _server_summary(number: u16, state: Option<&str>, message: &str) -> String {
+    let msg = match state {
+        Some(state) => format!("ERROR {} ({}): {}", number, state, message),
+        None => format!("ERROR {}: {}", number, message),
+    };
+    if number == 1153 {
+        // the server doesn't say how large the packet was, check_packet_len does
+        return format!("{}; reduce the batch size or split the value, or raise the server max_allowed_packet", msg);
+    }
+    msg
+}
+
+/// The MySQL server error number of `err`, `None` for client side errors.
+pub fn mysql_errno(err: &sqlx::Error) -> Option<u16> {
+    let db_err = err.as_database_error()?;
+    db_err.try_downcast_ref::<MySqlDatabaseError>().map(|e| e.number())
+}
+
+/// Whether `err` is a database error for a deadlock (1213), after which InnoDB has rolled
+/// back the whole transaction and it can be run again.
+pub fn is_deadlock(err: &DbError) -> bool {
+    err.kind == ErrorKind::Deadlock
+}
+
+/// Whether `err` is a packet above `max_allowed_packet`, sent (1153) or refused before sending
+/// by `mysql::check_packet_len`. The server closes the connection after a 1153, so a
+/// transaction it happened in is gone, retrying the same statement fails the same way.
+pub fn is_packet_too_large(err: &DbError) -> bool {
+    err.kind == ErrorKind::PacketTooLarge
+}
+
+/// Whether `err` is the fast failure of `pool::get_conn` while the circuit breaker of the
+/// database is open, see `pool::circuit_state`.
+pub fn is_circuit_open(err: &DbError) -> bool {
+    err.kind == ErrorKind::CircuitOpen
+}
+
+/// Whether `err` is a `Low` priority acquire shed by `pool::get_conn_priority`, to back off
+/// or skip the work.
+pub fn is_overloaded(err: &DbError) -> bool {
+    err.kind == ErrorKind::Overloaded
+}
+
+/// Whether `err` is an acquire `pool::get_conn` refused because the task already holds a
+/// connection of the exhausted pool in a transaction of `tx::run_in_transaction_retry` or
+/// `tx::batch_commit_insert`, run the query on that transaction instead.
+pub fn is_potential_self_deadlock(err: &DbError) -> bool {
+    err.kind == ErrorKind::PotentialSelfDeadlock
+}
+
+/// Logs `err` and wraps it into a `DbError` as `"<kind> <context> failed: <summary>"`.
+/// The verbose `{:?}` form goes to the debug log. Both are cut by `sql::set_max_query_log_len`,
+/// server errors quote sql and decode errors can quote values.
+pub(crate) fn database(kind: DatabaseKind, context: &str, err: sqlx::Error) -> DbError {
+    _database(kind, context, summary(&err), err)
+}
+
+/// `database` for a query decoding rows into `T`, a missing column also names the struct,
+/// `query result is missing column 'x' expected by struct User`.
+pub(crate) fn database_for<T>(kind: DatabaseKind, context: &str, err: sqlx::Error) -> DbError {
+    match &err {
+        sqlx::Error::ColumnNotFound(_) => {
+            let name = std::any::type_name::<T>();
+            // the path is noise next to the struct name, keep it when generics make it ambiguous
+            let name = if name.contains('<') { name } else { name.rsplit("::").next().unwrap_or(name) };
+            let summary = format!("{} expected by struct {}", summary(&err), name);
+            _database(kind, context, summary, err)
+        }
+        _ => database(kind, context, err),
+    }
+}
+
+fn _database(kind: DatabaseKind, context: &str, summary: String, err: sqlx::Error) -> DbError {
+    let msg = format!("{} {} failed: {}", kind, context, summary);
+    let msg = crate::sql::truncate_for_log(&msg).into_owned();
+    tracing::error!("{}", msg);
+    tracing::debug!("{}", crate::sql::truncate_for_log(&format!("{} {} failed: {:?}", kind, context, err)));
+    DbError::_from_sqlx(msg, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn kind_comes_from_the_sqlx_error() {
+        assert_eq!(DbError::from(sqlx::Error::RowNotFound).kind(), ErrorKind::NotFound);
+        assert_eq!(DbError::from(sqlx::Error::PoolTimedOut).kind(), ErrorKind::PoolExhausted);
+        assert_eq!(DbError::from(sqlx::Error::Protocol("Deadlock found, no row found".to_string())).kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn kind_is_not_read_from_the_message() {
+        let err = DbError::new(ErrorKind::Other, "mysql query failed: ERROR 1213 (40001): pool timed out, no row found");
+        assert!(!is_deadlock(&err));
+        assert_eq!(err.kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn packet_too_large_from_the_server() {
+        // MySqlDatabaseError can't be built outside sqlx, so the summary and kind of a 1153 are
+        // checked on the parts summary and of_sqlx read from it
+        let msg = _server_summary(1153, Some("08S01"), "Got a packet bigger than 'max_allowed_packet' bytes");
+        assert_eq!(msg, "ERROR 1153 (08S01): Got a packet bigger than 'max_allowed_packet' bytes; reduce the batch size or split the value, or raise the server max_allowed_packet");
+        assert_eq!(ErrorKind::_of_errno(1153), ErrorKind::PacketTooLarge);
+        assert!(is_packet_too_large(&DbError::new(ErrorKind::_of_errno(1153), msg)));
+        assert_eq!(_server_summary(1062, None, "Duplicate entry '1' for key 'PRIMARY'"), "ERROR 1062: Duplicate entry '1' for key 'PRIMARY'");
+    }
+
+    #[test]
+    fn packet_too_large_before_sending() {
+        let err = crate::mysql::check_packet_len(2 << 20, 1 << 20).unwrap_err();
+        assert!(is_packet_too_large(&err));
+        assert!(crate::mysql::check_packet_len(1 << 20, 1 << 20).is_ok());
+    }
+
+    #[test]
+    fn sqlx_error_is_the_source() {
+        let err = DbError::from_sqlx(DatabaseKind::MySql, "exec", sqlx::Error::RowNotFound);
+        assert_eq!(err.message(), "mysql exec failed: no rows returned by a query that expected to return at least one row");
+        let source = err.source().and_then(|e| e.downcast_ref::<sqlx::Error>());
+        assert!(matches!(source, Some(sqlx::Error::RowNotFound)));
+        assert!(DbError::new(ErrorKind::Other, "x").source().is_none());
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn every_error_carries_its_own_backtrace() {
+        use std::backtrace::BacktraceStatus;
+
+        let a = DbError::from(sqlx::Error::RowNotFound);
+        let b = DbError::from(sqlx::Error::RowNotFound);
+        assert_eq!(a.message(), b.message());
+        assert_eq!(a.backtrace().status(), BacktraceStatus::Captured);
+        assert_eq!(b.backtrace().status(), BacktraceStatus::Captured);
+    }
+
+    #[test]
+    fn converts_to_and_from_qx_error() {
+        assert!(matches!(Error::from(DbError::new(ErrorKind::Config, "missing MYSQL.URL")), Error::Env(msg) if msg == "missing MYSQL.URL"));
+        assert!(matches!(Error::from(DbError::new(ErrorKind::Deadlock, "x")), Error::Database(msg) if msg == "x"));
+        assert_eq!(DbError::from(Error::Env("x".to_string())).kind(), ErrorKind::Config);
+        assert_eq!(DbError::from(Error::Database("x".to_string())).kind(), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,38 @@
+//! `JSON` columns, behind the `json` feature.
+//!
+//! Bind any `Serialize` value with `json_arg`, read it back by decoding the column into
+//! `sqlx::types::Json<T>`, which needs `T: Deserialize`:
+//!
+//! ```ignore
+//! #[derive(serde::Serialize, serde::Deserialize)]
+//! struct Prefs { theme: String, notify: Notify }
+//!
+//! mysql::exec(&mut conn, mysql::query("UPDATE user SET prefs = ? WHERE id = ?").bind(json::json_arg(&prefs)?).bind(id)).await?;
+//! let (prefs,): (Json<Prefs>,) = mysql::exec_one(&mut conn, mysql::query_as("SELECT prefs FROM user WHERE id = ?").bind(id)).await?;
+//! let prefs = prefs.0;
+//! ```
+//!
+//! A nullable column decodes into `Option<Json<T>>`, `None` for SQL `NULL`. MySQL normalizes the
+//! JSON it stores: object keys are sorted and duplicates dropped, so the text read back may
+//! differ from the text written while deserializing to the same value.
+
+use sqlx::types::Json;
+
+use crate::error::{DbError, ErrorKind, Result};
+
+
+/// `value` ready to bind to a `JSON` column. A value serializing to `null`, such as a `None`
+/// of an `Option`, binds SQL `NULL` rather than the JSON `null` literal.
+///
+/// Fails when `value` can't be serialized, e.g. a map with non-string keys.
+pub fn json_arg<T: serde::Serialize>(value: &T) -> Result<Option<Json<serde_json::Value>>> {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::Null) => Ok(None),
+        Ok(value) => Ok(Some(Json(value))),
+        Err(err) => {
+            let err = format!("json_arg failed: {}", err);
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+    }
+}
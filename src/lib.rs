@@ -1,2 +1,16 @@
+pub mod args;
+pub mod cache;
+#[cfg(feature = "csv")]
+pub mod csv;
+pub mod error;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod migrate;
 pub mod mysql;
-pub mod pool;
\ No newline at end of file
+pub mod pool;
+pub mod read_only;
+pub mod set;
+#[cfg(feature = "spatial")]
+pub mod spatial;
+pub mod sql;
+pub mod tx;
\ No newline at end of file
@@ -0,0 +1,95 @@
+//! Versioned migrations run at startup, without `sqlx-cli`.
+//!
+//! ```ignore
+//! migrate::run_migrations(&mut conn, &[
+//!     (1, "CREATE TABLE user (id BIGINT PRIMARY KEY AUTO_INCREMENT, name VARCHAR(64) NOT NULL)"),
+//!     (2, "ALTER TABLE user ADD COLUMN email VARCHAR(255) NULL"),
+//! ]).await?;
+//! ```
+//!
+//! Applied versions are recorded in a `_migrations` table. Never edit or renumber a migration
+//! once it ran somewhere, add a new one instead, the sql of an applied version isn't checked.
+
+use sqlx::{Connection, Executor, MySqlConnection};
+
+
+use crate::error::{self, DatabaseKind, DbError, ErrorKind, Result};
+
+const KIND: DatabaseKind = DatabaseKind::MySql;
+
+// seconds to wait for another instance running the migrations
+const LOCK_TIMEOUT_SECS: u32 = 60;
+
+
+/// Applies the migrations of `migrations` not recorded in `_migrations` yet, by ascending
+/// version, creating the table on first use. Stops at the first failing migration, which is
+/// not recorded, so fixing it and starting again picks up from there.
+///
+/// Each migration runs in a transaction with the insert of its version, and may hold several
+/// statements separated by `;`. MySQL commits DDL implicitly, so a failed migration that ran
+/// `CREATE` / `ALTER` statements before the failing one keeps them, prefer one DDL statement
+/// per migration. Runs under `GET_LOCK('_migrations')`, so instances starting together apply
+/// each migration once.
+pub async fn run_migrations(conn: &mut MySqlConnection, migrations: &[(i64, &str)]) -> Result<()> {
+    let mut sorted: Vec<(i64, &str)> = migrations.to_vec();
+    sorted.sort_by_key(|(version, _)| *version);
+    if let Some(pair) = sorted.windows(2).find(|a| a[0].0 == a[1].0) {
+        let err = format!("run_migrations failed: version {} is listed twice", pair[0].0);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    let res = sqlx::query_scalar::<_, Option<i64>>("SELECT GET_LOCK('_migrations', ?)")
+        .bind(LOCK_TIMEOUT_SECS)
+        .fetch_one(&mut *conn).await;
+    match res {
+        Ok(Some(1)) => {}
+        Ok(_) => {
+            let err = format!("run_migrations failed: _migrations lock not acquired in {} seconds", LOCK_TIMEOUT_SECS);
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+        Err(err) => {
+            return Err(error::database(KIND, "run_migrations lock", err));
+        }
+    }
+    let res = _run(conn, &sorted).await;
+    if let Err(err) = sqlx::query("SELECT RELEASE_LOCK('_migrations')").execute(&mut *conn).await {
+        tracing::warn!("run_migrations unlock failed: {}", error::summary(&err));
+    }
+    res
+}
+
+async fn _run(conn: &mut MySqlConnection, migrations: &[(i64, &str)]) -> Result<()> {
+    let res = conn.execute("CREATE TABLE IF NOT EXISTS _migrations (version BIGINT NOT NULL PRIMARY KEY, applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)").await;
+    if let Err(err) = res {
+        return Err(error::database(KIND, "run_migrations create table", err));
+    }
+    let applied = match sqlx::query_scalar::<_, i64>("SELECT version FROM _migrations").fetch_all(&mut *conn).await {
+        Ok(applied) => applied,
+        Err(err) => {
+            return Err(error::database(KIND, "run_migrations", err));
+        }
+    };
+    for (version, sql) in migrations.iter().filter(|(version, _)| !applied.contains(version)) {
+        let context = format!("run_migrations version {}", version);
+        let mut trans = match conn.begin().await {
+            Ok(trans) => trans,
+            Err(err) => {
+                return Err(error::database(KIND, &context, err));
+            }
+        };
+        // dropping trans on an error rolls it back
+        if let Err(err) = (&mut *trans).execute(*sql).await {
+            return Err(error::database(KIND, &context, err));
+        }
+        let res = sqlx::query("INSERT INTO _migrations (version) VALUES (?)").bind(version).execute(&mut *trans).await;
+        if let Err(err) = res {
+            return Err(error::database(KIND, &context, err));
+        }
+        if let Err(err) = trans.commit().await {
+            return Err(error::database(KIND, &context, err));
+        }
+        tracing::info!("migration {} applied", version);
+    }
+    Ok(())
+}
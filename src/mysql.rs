@@ -1,73 +1,1362 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::str::FromStr;
 
+use futures::{Stream, StreamExt};
+use tracing::{Instrument, Span};
 #[allow(unused)]
-
-
 use sqlx::database::HasArguments;
 use sqlx::query::Query;
-use sqlx::{IntoArguments, MySql, MySqlConnection};
-use sqlx::{mysql::{MySqlRow, MySqlArguments}, query::QueryAs, FromRow};
+use sqlx::{pool::PoolConnection, Connection, Execute, Executor, IntoArguments, MySql, MySqlConnection, Pool, QueryBuilder, Row, Transaction};
+use sqlx::{mysql::{MySqlRow, MySqlArguments, MySqlTypeInfo}, query::{QueryAs, QueryScalar}, Column, FromRow, TypeInfo};
+
+
+use crate::error::{self, DatabaseKind, DbError, ErrorKind, Result};
+
+const KIND: DatabaseKind = DatabaseKind::MySql;
+
+
+/// What the `exec*` helpers run on: `&mut MySqlConnection`, `&mut PoolConnection<MySql>`,
+/// `&mut Transaction<'_, MySql>`, `&mut tx::TransactionGuard` or `&Pool<MySql>`, which takes a
+/// connection from the pool for the one query.
+///
+/// sqlx 0.7 has `Executor` only for the connection and the pool, and its `Acquire` makes the
+/// helper futures fail the `Send` check inside `BoxFuture` closures like the ones of
+/// `tx::run_in_transaction_retry`, so the helpers resolve the executor before their future starts.
+pub trait Conn<'c> {
+    type Executor: Executor<'c, Database = MySql>;
+
+    fn executor(self) -> Self::Executor;
+}
+
+impl<'c> Conn<'c> for &'c mut MySqlConnection {
+    type Executor = &'c mut MySqlConnection;
+
+    fn executor(self) -> Self::Executor {
+        self
+    }
+}
+
+impl<'c> Conn<'c> for &'c mut PoolConnection<MySql> {
+    type Executor = &'c mut MySqlConnection;
+
+    fn executor(self) -> Self::Executor {
+        &mut *self
+    }
+}
+
+impl<'c, 't> Conn<'c> for &'c mut Transaction<'t, MySql> {
+    type Executor = &'c mut MySqlConnection;
+
+    fn executor(self) -> Self::Executor {
+        &mut *self
+    }
+}
+
+impl<'c, 't> Conn<'c> for &'c mut crate::tx::TransactionGuard<'t> {
+    type Executor = &'c mut MySqlConnection;
+
+    fn executor(self) -> Self::Executor {
+        &mut **self
+    }
+}
+
+impl<'c> Conn<'c> for &'c Pool<MySql> {
+    type Executor = &'c Pool<MySql>;
+
+    fn executor(self) -> Self::Executor {
+        self
+    }
+}
 
-use qx_rs_server::err::{Error, Result};
+// lets the futures of the helpers borrow for 'c and 'q without capturing more, as `impl Trait`
+// in return position only captures the lifetimes its bounds name
+#[doc(hidden)]
+pub trait Captures<'a> {}
 
+impl<'a, T: ?Sized> Captures<'a> for T {}
 
-pub async fn exec_arr<'q, T>(conn: &mut MySqlConnection, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Vec<T>> 
+pub fn exec_arr<'c, 'q, T>(conn: impl Conn<'c>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> impl Future<Output = Result<Vec<T>>> + Send + Captures<'c> + Captures<'q>
 where
     T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
 {
-    let res = sql_as.fetch_all(&mut *conn).await;
+    _exec_arr(conn.executor(), sql_as)
+}
+
+async fn _exec_arr<'c, 'q, T, E>(conn: E, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    E: Executor<'c, Database = MySql>,
+{
+    let sql = sql_as.sql();
+    let span = _span("exec_arr", sql);
+    let _sampled = _sample("exec_arr", sql);
+    let res = sql_as.fetch_all(conn).instrument(span).await;
     match res {
         Ok(users) => {
+            if tracing::enabled!(tracing::Level::DEBUG) {
+                tracing::debug!(rows = users.len(), sql_len = sql.len(), "exec_arr fetched");
+            }
             Ok(users)
         },
         Err(err) => {
+            return Err(error::database_for::<T>(KIND, "exec_arr", err));
+        }
+    }
+}
+
+/// Like `exec_arr`, but fails with `result exceeded N rows` as soon as row `max_rows + 1`
+/// arrives, so a `WHERE` clause gone wrong can't fill the memory with millions of rows.
+///
+/// Rows are decoded as they arrive and none past the cap is kept. The server has sent the rest
+/// of the result already, the connection reads it away before its next query, use a `LIMIT`
+/// too where the query allows it.
+pub fn exec_arr_capped<'c, 'q, T>(conn: impl Conn<'c>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>, max_rows: usize) -> impl Future<Output = Result<Vec<T>>> + Send + Captures<'c> + Captures<'q>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    _exec_arr_capped(conn.executor(), sql_as, max_rows)
+}
+
+async fn _exec_arr_capped<'c, 'q, T, E>(conn: E, sql_as: QueryAs<'q, MySql, T, MySqlArguments>, max_rows: usize) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("exec_arr_capped", sql_as.sql());
+    let _sampled = _sample("exec_arr_capped", sql_as.sql());
+    let res = async {
+        let mut rows = sql_as.fetch(conn);
+        let mut arr = Vec::new();
+        while let Some(row) = rows.next().await.transpose()? {
+            if arr.len() == max_rows {
+                return Ok(None);
+            }
+            arr.push(row);
+        }
+        Ok::<_, sqlx::Error>(Some(arr))
+    }.instrument(span).await;
+    match res {
+        Ok(Some(arr)) => Ok(arr),
+        Ok(None) => {
+            let err = format!("exec_arr_capped failed: result exceeded {} rows", max_rows);
             tracing::error!("{}", err);
-            return Err(Error::Database(format!("exec_arr failed:{:?}", err)));
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+        Err(err) => {
+            return Err(error::database_for::<T>(KIND, "exec_arr_capped", err));
         }
     }
 }
 
-pub async fn exec_one<'q, T>(conn: &mut MySqlConnection, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<T> 
+pub fn exec_one<'c, 'q, T>(conn: impl Conn<'c>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> impl Future<Output = Result<T>> + Send + Captures<'c> + Captures<'q>
 where
     T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
 {
-    let res = sql_as.fetch_one(&mut *conn).await;
+    _exec_one(conn.executor(), sql_as)
+}
+
+async fn _exec_one<'c, 'q, T, E>(conn: E, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<T>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("exec_one", sql_as.sql());
+    let _sampled = _sample("exec_one", sql_as.sql());
+    let res = sql_as.fetch_one(conn).instrument(span).await;
     match res {
         Ok(arr) => Ok(arr),
         Err(err) => {
+            return Err(error::database_for::<T>(KIND, "exec_one", err));
+        }
+    }
+}
+
+/// Like `exec_one`, but fails unless the query matches exactly one row, with distinct
+/// "no row found" and "more than one row found" errors, to catch a `WHERE` clause that
+/// matches more than intended. Stops reading after the second row.
+pub fn exec_exactly_one<'c, 'q, T>(conn: impl Conn<'c>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> impl Future<Output = Result<T>> + Send + Captures<'c> + Captures<'q>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    _exec_exactly_one(conn.executor(), sql_as)
+}
+
+async fn _exec_exactly_one<'c, 'q, T, E>(conn: E, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<T>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("exec_exactly_one", sql_as.sql());
+    let _sampled = _sample("exec_exactly_one", sql_as.sql());
+    let res = async {
+        let mut rows = sql_as.fetch(conn);
+        let first = rows.next().await.transpose()?;
+        let second = rows.next().await.transpose()?;
+        Ok::<_, sqlx::Error>((first, second.is_some()))
+    }.instrument(span).await;
+    match res {
+        Ok((Some(row), false)) => Ok(row),
+        Ok((first, _)) => {
+            let (kind, err) = match first {
+                None => (ErrorKind::NotFound, "exec_exactly_one failed: no row found"),
+                Some(_) => (ErrorKind::Other, "exec_exactly_one failed: more than one row found"),
+            };
             tracing::error!("{}", err);
-            return Err(Error::Database(format!("exec_one failed:{:?}", err)));
+            return Err(DbError::new(kind, err));
+        }
+        Err(err) => {
+            return Err(error::database_for::<T>(KIND, "exec_exactly_one", err));
         }
     }
 }
 
-pub async fn exec_opt_one<'q, T>(conn: &mut MySqlConnection, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Option<T>> 
+/// The first row, `None` only when the query returns no row. A row that is there but fails to
+/// decode is an error like any other, never `None`, so a malformed row can't pass for absent.
+pub fn exec_opt_one<'c, 'q, T>(conn: impl Conn<'c>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> impl Future<Output = Result<Option<T>>> + Send + Captures<'c> + Captures<'q>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    _exec_opt_one(conn.executor(), sql_as)
+}
+
+async fn _exec_opt_one<'c, 'q, T, E>(conn: E, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Option<T>>
 where
     T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    E: Executor<'c, Database = MySql>,
 {
-    let res = sql_as.fetch_optional(&mut *conn).await;
+    let span = _span("exec_opt_one", sql_as.sql());
+    let _sampled = _sample("exec_opt_one", sql_as.sql());
+    let res = sql_as.fetch_optional(conn).instrument(span).await;
     match res {
         Ok(a) => Ok(a),
         Err(err) => {
+            return Err(error::database_for::<T>(KIND, "exec_opt_one", err));
+        }
+    }
+}
+
+/// First column of the first row, or `default` when there is no row, e.g. for
+/// `SELECT value FROM settings WHERE name = ?`. A `NULL` value still needs `T = Option<_>`.
+pub fn exec_scalar_or<'c, 'q, T>(conn: impl Conn<'c>, sql: QueryScalar<'q, MySql, T, MySqlArguments>, default: T) -> impl Future<Output = Result<T>> + Send + Captures<'c> + Captures<'q>
+where
+    (T,): for<'r> FromRow<'r, MySqlRow>,
+    T: Send + Unpin,
+{
+    _exec_scalar_or(conn.executor(), sql, default)
+}
+
+async fn _exec_scalar_or<'c, 'q, T, E>(conn: E, sql: QueryScalar<'q, MySql, T, MySqlArguments>, default: T) -> Result<T>
+where
+    (T,): for<'r> FromRow<'r, MySqlRow>,
+    T: Send + Unpin,
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("exec_scalar_or", sql.sql());
+    let _sampled = _sample("exec_scalar_or", sql.sql());
+    let res = sql.fetch_optional(conn).instrument(span).await;
+    match res {
+        Ok(a) => Ok(a.unwrap_or(default)),
+        Err(err) => {
+            return Err(error::database(KIND, "exec_scalar_or", err));
+        }
+    }
+}
+
+/// Streams the raw rows of `sql` through `map`, for rows `FromRow` can't express
+/// (computed fields, column names only known at runtime). Fetch and `map` errors both
+/// end up as stream items.
+pub fn exec_stream_map<'a, T, F>(conn: &'a mut MySqlConnection, sql: &'a str, map: F) -> impl Stream<Item = Result<T>> + 'a
+where
+    T: 'a,
+    F: Fn(&MySqlRow) -> Result<T> + 'a,
+{
+    sqlx::query(sql).fetch(conn).map(move |res| match res {
+        Ok(row) => map(&row),
+        Err(err) => {
+            return Err(error::database(KIND, "exec_stream_map", err));
+        }
+    })
+}
+
+/// Fetches all rows and indexes them by `key`. On duplicate keys the last row wins.
+pub async fn fetch_map<'q, T, K>(conn: &mut MySqlConnection, sql_as: QueryAs<'q, MySql, T, MySqlArguments>, key: impl Fn(&T) -> K) -> Result<HashMap<K, T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    K: Eq + Hash,
+{
+    let span = _span("fetch_map", sql_as.sql());
+    let _sampled = _sample("fetch_map", sql_as.sql());
+    let res = sql_as.fetch_all(&mut *conn).instrument(span).await;
+    match res {
+        Ok(arr) => Ok(arr.into_iter().map(|a| (key(&a), a)).collect()),
+        Err(err) => {
+            return Err(error::database_for::<T>(KIND, "fetch_map", err));
+        }
+    }
+}
+
+/// Fetches all rows and groups them by `key`, each group keeps the row order of the result.
+pub async fn fetch_grouped<'q, T, K>(conn: &mut MySqlConnection, sql_as: QueryAs<'q, MySql, T, MySqlArguments>, key: impl Fn(&T) -> K) -> Result<HashMap<K, Vec<T>>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    K: Eq + Hash,
+{
+    let span = _span("fetch_grouped", sql_as.sql());
+    let _sampled = _sample("fetch_grouped", sql_as.sql());
+    let res = sql_as.fetch_all(&mut *conn).instrument(span).await;
+    match res {
+        Ok(arr) => {
+            let mut map: HashMap<K, Vec<T>> = HashMap::new();
+            for a in arr {
+                map.entry(key(&a)).or_default().push(a);
+            }
+            Ok(map)
+        }
+        Err(err) => {
+            return Err(error::database_for::<T>(KIND, "fetch_grouped", err));
+        }
+    }
+}
+
+/// Returns `(rows_affected, last_insert_id)`.
+///
+/// `last_insert_id` only comes from an `AUTO_INCREMENT` column. For tables keyed by a
+/// string / UUID it is always `0` and meaningless, use `insert_with_key` for those.
+pub fn exec<'c, 'q>(conn: impl Conn<'c>, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> impl Future<Output = Result<(u64, u64)>> + Send + Captures<'c> + Captures<'q> {
+    _exec(conn.executor(), sql)
+}
+
+async fn _exec<'c, 'q, E>(conn: E, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<(u64, u64)>
+where
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("exec", sql.sql());
+    let _sampled = _sample("exec", sql.sql());
+    let res = sql.execute(conn).instrument(span).await;
+    match res {
+        Ok(a) => Ok((a.rows_affected(), a.last_insert_id())),
+        Err(err) => {
+            return Err(error::database(KIND, "exec", err));
+        }
+    }
+}
+
+/// Same as `exec`, with a named result.
+pub fn exec_result<'c, 'q>(conn: impl Conn<'c>, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> impl Future<Output = Result<ExecResult>> + Send + Captures<'c> + Captures<'q> {
+    _exec_result(conn.executor(), sql)
+}
+
+async fn _exec_result<'c, 'q, E>(conn: E, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<ExecResult>
+where
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("exec_result", sql.sql());
+    let _sampled = _sample("exec_result", sql.sql());
+    let res = sql.execute(conn).instrument(span).await;
+    match res {
+        Ok(a) => Ok(ExecResult {
+            rows_affected: a.rows_affected(),
+            last_insert_id: a.last_insert_id(),
+        }),
+        Err(err) => {
+            return Err(error::database(KIND, "exec_result", err));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecResult {
+    pub rows_affected: u64,
+    /// raw `LAST_INSERT_ID()`, see `exec`
+    pub last_insert_id: u64,
+}
+
+impl ExecResult {
+    /// `last_insert_id` as `i64`, failing instead of wrapping negative for `BIGINT UNSIGNED` ids above `i64::MAX`.
+    pub fn last_insert_id_i64(&self) -> Result<i64> {
+        match i64::try_from(self.last_insert_id) {
+            Ok(id) => Ok(id),
+            Err(_) => {
+                let err = format!("last_insert_id {} overflows i64", self.last_insert_id);
+                tracing::error!("{}", err);
+                return Err(DbError::new(ErrorKind::Other, err));
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExecResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rows_affected={} last_insert_id={}", self.rows_affected, self.last_insert_id)
+    }
+}
+
+impl From<ExecResult> for (u64, u64) {
+    fn from(res: ExecResult) -> Self {
+        (res.rows_affected, res.last_insert_id)
+    }
+}
+
+/// A row of `SHOW WARNINGS`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Warning {
+    /// `Note`, `Warning` or `Error`
+    pub level: String,
+    /// the error number, e.g. 1265 for `Data truncated for column`
+    pub code: u16,
+    pub message: String,
+}
+
+/// Runs `sql` like `exec_result`, then `SHOW WARNINGS` on the same connection, to see the
+/// truncations, implicit conversions and other non-fatal problems MySQL otherwise only
+/// records. Strict mode makes most data problems errors, the rest still only warn.
+///
+/// Warnings belong to the connection and the next statement replaces them, this reads them
+/// right after `sql` and costs that extra round trip. Takes the connection itself, a
+/// transaction passes `&mut *tx`. At most `max_error_count` (1024 by default) are kept.
+pub async fn exec_with_warnings<'q>(conn: &mut MySqlConnection, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<(ExecResult, Vec<Warning>)> {
+    let res = _exec_result(&mut *conn, sql).await?;
+    let rows = match sqlx::query("SHOW WARNINGS").fetch_all(&mut *conn).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(error::database(KIND, "exec_with_warnings", err));
+        }
+    };
+    let warnings = rows.iter().map(|row| Ok::<_, sqlx::Error>(Warning {
+        level: _show_str(row, "Level")?.unwrap_or_default(),
+        code: _show_u64(row, "Code")?.unwrap_or_default() as u16,
+        message: _show_str(row, "Message")?.unwrap_or_default(),
+    })).collect::<std::result::Result<Vec<_>, _>>();
+    match warnings {
+        Ok(warnings) => {
+            if !warnings.is_empty() {
+                tracing::debug!(warnings = warnings.len(), "exec_with_warnings");
+            }
+            Ok((res, warnings))
+        }
+        Err(err) => {
+            return Err(error::database(KIND, "exec_with_warnings", err));
+        }
+    }
+}
+
+/// Deletes rows whose `id_column` is in `ids`, issuing one `DELETE ... IN (...)` per `chunk` ids.
+///
+/// Chunks run one after another on `conn` so each statement only locks its own rows.
+/// Returns the total rows deleted, an empty `ids` is a no-op.
+pub async fn delete_in_chunks(conn: &mut MySqlConnection, table: &str, id_column: &str, ids: &[i64], chunk: usize) -> Result<u64> {
+    _check_ident(table)?;
+    _check_ident(id_column)?;
+    if chunk == 0 {
+        return Err(DbError::new(ErrorKind::Other, "delete_in_chunks failed: chunk must be positive".to_string()));
+    }
+    let mut total = 0;
+    for part in ids.chunks(chunk) {
+        let placeholders = vec!["?"; part.len()].join(", ");
+        let sql = format!("DELETE FROM `{}` WHERE `{}` IN ({})", table, id_column, placeholders);
+        let mut q = sqlx::query(&sql);
+        for id in part {
+            q = q.bind(id);
+        }
+        let res = q.execute(&mut *conn).await;
+        match res {
+            Ok(a) => total += a.rows_affected(),
+            Err(err) => {
+                return Err(error::database(KIND, "delete_in_chunks", err));
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Fetches the rows of `sql` for `values`, where `sql` has one `IN (?)` in any case and spacing,
+/// its only placeholder outside literals and comments, expanded to a `?` per value:
+///
+/// ```ignore
+/// let users: Vec<User> = mysql::fetch_in(&mut conn, "SELECT * FROM user WHERE id IN (?)", &ids, 1000).await?;
+/// ```
+///
+/// Lists longer than `max_in_list` are split into queries of at most `max_in_list` values, run
+/// one after another, and their rows appended in order. Past some thousands of values the
+/// optimizer may give up on the index (`range_optimizer_max_mem_size`) and scan the table,
+/// smaller lists keep each query on the index. `0` never splits. A split query applies its
+/// `ORDER BY`, `LIMIT`, `GROUP BY` and `DISTINCT` to each part, not to the merged rows, and the
+/// parts are not one consistent read unless `conn` is in a transaction. An empty `values`
+/// returns no rows without a query.
+pub async fn fetch_in<T, V>(conn: &mut MySqlConnection, sql: &str, values: &[V], max_in_list: usize) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    V: for<'q> sqlx::Encode<'q, MySql> + sqlx::Type<MySql> + Sync,
+{
+    let (start, end) = match crate::sql::in_list_placeholder(sql) {
+        Some(range) => range,
+        None => {
+            let err = format!("fetch_in failed: sql must have one IN (?) and no other placeholder: {}", crate::sql::truncate_for_log(sql));
             tracing::error!("{}", err);
-            return Err(Error::Database(format!("exec_opt_one failed:{:?}", err)));
+            return Err(DbError::new(ErrorKind::Other, err));
         }
+    };
+    if values.is_empty() {
+        return Ok(Vec::new());
     }
+    let mut rows = Vec::new();
+    for (range, sql) in _in_chunks(sql, (start, end), values.len(), max_in_list) {
+        let mut q = sqlx::query_as::<_, T>(&sql);
+        for value in &values[range] {
+            q = q.bind(value);
+        }
+        let span = _span("fetch_in", &sql);
+        let _sampled = _sample("fetch_in", &sql);
+        let res = q.fetch_all(&mut *conn).instrument(span).await;
+        match res {
+            Ok(arr) => rows.extend(arr),
+            Err(err) => {
+                return Err(error::database_for::<T>(KIND, "fetch_in", err));
+            }
+        }
+    }
+    Ok(rows)
+}
+
+// the values range and the sql of each query of fetch_in, `in_list` the byte range of its `IN (?)`
+fn _in_chunks(sql: &str, in_list: (usize, usize), len: usize, max_in_list: usize) -> Vec<(std::ops::Range<usize>, String)> {
+    let chunk = if max_in_list == 0 { len.max(1) } else { max_in_list };
+    (0..len).step_by(chunk).map(|start| {
+        let end = (start + chunk).min(len);
+        let placeholders = vec!["?"; end - start].join(", ");
+        (start..end, format!("{}IN ({}){}", &sql[..in_list.0], placeholders, &sql[in_list.1..]))
+    }).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortDir {
+    Asc,
+    Desc,
+}
+
+impl FromStr for SortDir {
+    type Err = DbError;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "ASC" => Ok(SortDir::Asc),
+            "DESC" => Ok(SortDir::Desc),
+            _ => {
+                let err = format!("invalid sort direction: {:?}", s);
+                tracing::error!("{}", err);
+                return Err(DbError::new(ErrorKind::Other, err));
+            }
+        }
+    }
+}
+
+/// Appends `ORDER BY <requested> <dir>` to `builder` if `requested` is one of the `allowed`
+/// columns, the safe way to sort by a user supplied column name.
+pub fn order_by(builder: &mut QueryBuilder<'_, MySql>, requested: &str, allowed: &[&str], dir: SortDir) -> Result<()> {
+    if !allowed.contains(&requested) {
+        let err = format!("sorting by {:?} is not allowed", requested);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    _check_ident(requested)?;
+    builder.push(" ORDER BY `");
+    builder.push(requested);
+    builder.push(match dir {
+        SortDir::Asc => "` ASC",
+        SortDir::Desc => "` DESC",
+    });
+    Ok(())
+}
+
+tokio::task_local! {
+    static QUERY_TAG: &'static str;
+}
+
+/// Runs `fut` with the queries it makes tagged `tag`, e.g. `"checkout"` or `"search"`, to
+/// attribute database load to the business operation.
+///
+/// The tag is the `db.query.tag` attribute of the `db.query` spans of the `otel` feature, read
+/// it anywhere inside with `query_tag`. It follows `fut` across `.await` but not into tasks it
+/// spawns. An inner `with_query_tag` replaces the tag of the outer one until it ends.
+///
+/// Every distinct tag becomes its own series in metrics backends, keep tags to a small fixed
+/// set of operation names, never ids, user names or other values taken from requests.
+///
+/// ```ignore
+/// let order = mysql::with_query_tag("checkout", async {
+///     let mut conn = pool::get_conn().await?;
+///     mysql::exec_one::<Order>(&mut conn, mysql::query_as("SELECT * FROM `order` WHERE id = ?").bind(id)).await
+/// }).await?;
+/// ```
+pub async fn with_query_tag<F: Future>(tag: &'static str, fut: F) -> F::Output {
+    QUERY_TAG.scope(tag, fut).await
+}
+
+/// The tag of the innermost `with_query_tag` running this code.
+pub fn query_tag() -> Option<&'static str> {
+    QUERY_TAG.try_with(|a| *a).ok()
+}
+
+// logs the sql and time of a query sampled by MYSQL.QUERY_LOG_SAMPLE_RATE when dropped
+struct QuerySample<'a> {
+    name: &'static str,
+    sql: &'a str,
+    start: std::time::Instant,
+}
+
+// decides before touching the sql, an unsampled query only pays for the random number
+fn _sample<'a>(name: &'static str, sql: &'a str) -> Option<QuerySample<'a>> {
+    if !crate::sql::sample_query() {
+        return None;
+    }
+    Some(QuerySample { name, sql, start: std::time::Instant::now() })
+}
+
+impl Drop for QuerySample<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        tracing::info!(query = self.name, elapsed_ms = elapsed.as_millis() as u64, "sampled query took {:?}: {}", elapsed, crate::sql::truncate_for_log(&crate::sql::sanitize(self.sql)));
+    }
+}
+
+// with the `otel` feature, a span following the OpenTelemetry database conventions. sqlx
+// queries keep their text, `Execute::sql()`, so every helper passes the sql it runs
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+fn _span(name: &'static str, sql: &str) -> Span {
+    #[cfg(feature = "otel")]
+    {
+        let span = tracing::info_span!(
+            "db.query",
+            otel.name = name,
+            otel.kind = "client",
+            db.system = "mysql",
+            db.operation = %crate::sql::leading_keyword(sql).to_uppercase(),
+            db.statement = %crate::sql::truncate_for_log(&crate::sql::sanitize(sql)),
+            db.query.tag = tracing::field::Empty,
+        );
+        if let Some(tag) = query_tag() {
+            span.record("db.query.tag", tag);
+        }
+        span
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        Span::none()
+    }
+}
+
+// reserved words of MySQL 8.0, a table named like one must always be quoted, see safe_table
+const RESERVED_WORDS: &[&str] = &[
+    "ACCESSIBLE", "ADD", "ALL", "ALTER", "ANALYZE", "AND", "AS", "ASC", "ASENSITIVE", "BEFORE",
+    "BETWEEN", "BIGINT", "BINARY", "BLOB", "BOTH", "BY", "CALL", "CASCADE", "CASE", "CHANGE",
+    "CHAR", "CHARACTER", "CHECK", "COLLATE", "COLUMN", "CONDITION", "CONSTRAINT", "CONTINUE",
+    "CONVERT", "CREATE", "CROSS", "CUBE", "CUME_DIST", "CURRENT_DATE", "CURRENT_TIME",
+    "CURRENT_TIMESTAMP", "CURRENT_USER", "CURSOR", "DATABASE", "DATABASES", "DAY_HOUR",
+    "DAY_MICROSECOND", "DAY_MINUTE", "DAY_SECOND", "DEC", "DECIMAL", "DECLARE", "DEFAULT",
+    "DELAYED", "DELETE", "DENSE_RANK", "DESC", "DESCRIBE", "DETERMINISTIC", "DISTINCT",
+    "DISTINCTROW", "DIV", "DOUBLE", "DROP", "DUAL", "EACH", "ELSE", "ELSEIF", "EMPTY", "ENCLOSED",
+    "ESCAPED", "EXCEPT", "EXISTS", "EXIT", "EXPLAIN", "FALSE", "FETCH", "FIRST_VALUE", "FLOAT",
+    "FLOAT4", "FLOAT8", "FOR", "FORCE", "FOREIGN", "FROM", "FULLTEXT", "FUNCTION", "GENERATED",
+    "GET", "GRANT", "GROUP", "GROUPING", "GROUPS", "HAVING", "HIGH_PRIORITY", "HOUR_MICROSECOND",
+    "HOUR_MINUTE", "HOUR_SECOND", "IF", "IGNORE", "IN", "INDEX", "INFILE", "INNER", "INOUT",
+    "INSENSITIVE", "INSERT", "INT", "INT1", "INT2", "INT3", "INT4", "INT8", "INTEGER", "INTERSECT",
+    "INTERVAL", "INTO", "IO_AFTER_GTIDS", "IO_BEFORE_GTIDS", "IS", "ITERATE", "JOIN", "JSON_TABLE",
+    "KEY", "KEYS", "KILL", "LAG", "LAST_VALUE", "LATERAL", "LEAD", "LEADING", "LEAVE", "LEFT",
+    "LIKE", "LIMIT", "LINEAR", "LINES", "LOAD", "LOCALTIME", "LOCALTIMESTAMP", "LOCK", "LONG",
+    "LONGBLOB", "LONGTEXT", "LOOP", "LOW_PRIORITY", "MASTER_BIND", "MASTER_SSL_VERIFY_SERVER_CERT",
+    "MATCH", "MAXVALUE", "MEDIUMBLOB", "MEDIUMINT", "MEDIUMTEXT", "MIDDLEINT", "MINUTE_MICROSECOND",
+    "MINUTE_SECOND", "MOD", "MODIFIES", "NATURAL", "NOT", "NO_WRITE_TO_BINLOG", "NTH_VALUE",
+    "NTILE", "NULL", "NUMERIC", "OF", "ON", "OPTIMIZE", "OPTIMIZER_COSTS", "OPTION", "OPTIONALLY",
+    "OR", "ORDER", "OUT", "OUTER", "OUTFILE", "OVER", "PARTITION", "PERCENT_RANK", "PRECISION",
+    "PRIMARY", "PROCEDURE", "PURGE", "RANGE", "RANK", "READ", "READS", "READ_WRITE", "REAL",
+    "RECURSIVE", "REFERENCES", "REGEXP", "RELEASE", "RENAME", "REPEAT", "REPLACE", "REQUIRE",
+    "RESIGNAL", "RESTRICT", "RETURN", "REVOKE", "RIGHT", "RLIKE", "ROW", "ROWS", "ROW_NUMBER",
+    "SCHEMA", "SCHEMAS", "SECOND_MICROSECOND", "SELECT", "SENSITIVE", "SEPARATOR", "SET", "SHOW",
+    "SIGNAL", "SMALLINT", "SPATIAL", "SPECIFIC", "SQL", "SQLEXCEPTION", "SQLSTATE", "SQLWARNING",
+    "SQL_BIG_RESULT", "SQL_CALC_FOUND_ROWS", "SQL_SMALL_RESULT", "SSL", "STARTING", "STORED",
+    "STRAIGHT_JOIN", "SYSTEM", "TABLE", "TERMINATED", "THEN", "TINYBLOB", "TINYINT", "TINYTEXT",
+    "TO", "TRAILING", "TRIGGER", "TRUE", "UNDO", "UNION", "UNIQUE", "UNLOCK", "UNSIGNED", "UPDATE",
+    "USAGE", "USE", "USING", "UTC_DATE", "UTC_TIME", "UTC_TIMESTAMP", "VALUES", "VARBINARY",
+    "VARCHAR", "VARCHARACTER", "VARYING", "VIRTUAL", "WHEN", "WHERE", "WHILE", "WINDOW", "WITH",
+    "WRITE", "XOR", "YEAR_MONTH", "ZEROFILL",
+];
+
+/// `name` backtick-quoted for a table name computed at runtime, e.g. `events_2024_06` of a
+/// table per month:
+///
+/// ```ignore
+/// let sql = format!("SELECT * FROM {} WHERE id = ?", mysql::safe_table(&format!("events_{}", month))?);
+/// ```
+///
+/// Table names can't be bound as `?`, so this is the only sanctioned way to put one into sql,
+/// never interpolate a name without it. Only ASCII letters, digits and `_` up to 64 characters
+/// pass, not all digits and not a reserved word, anything else fails.
+pub fn safe_table(name: &str) -> Result<String> {
+    _check_ident(name)?;
+    if name.bytes().all(|c| c.is_ascii_digit()) || RESERVED_WORDS.iter().any(|a| a.eq_ignore_ascii_case(name)) {
+        let err = format!("invalid table name: {:?}", name);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    Ok(format!("`{}`", name))
 }
 
+fn _check_ident(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        let err = format!("invalid identifier: {:?}", name);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    Ok(())
+}
 
-pub async fn exec<'q>(conn: &mut MySqlConnection, sql: Query<'q, MySql, <MySql as HasArguments<'_>>::Arguments>) -> Result<(u64, u64)> 
+/// Runs an insert into a table whose key is supplied by the caller and echoes `key` back on success.
+pub async fn insert_with_key<'q, K>(conn: &mut MySqlConnection, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>, key: K) -> Result<K>
 {
     let res = sql.execute(&mut *conn).await;
     match res {
-        Ok(a) => Ok((a.rows_affected(), a.last_insert_id())),
+        Ok(_) => Ok(key),
+        Err(err) => {
+            return Err(error::database(KIND, "insert_with_key", err));
+        }
+    }
+}
+
+/// Runs an insert guarded by a unique idempotency column, `Ok(true)` when the row was inserted.
+///
+/// With `unique_violation_ok` a duplicate key error (1062) means the row exists already and
+/// comes back as `Ok(false)`, e.g. for a webhook delivered twice. Make sure the idempotency
+/// column is the only unique key the insert can hit, a duplicate on any other key is also 1062.
+pub fn insert_idempotent<'c, 'q>(conn: impl Conn<'c>, insert_sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>, unique_violation_ok: bool) -> impl Future<Output = Result<bool>> + Send + Captures<'c> + Captures<'q> {
+    _insert_idempotent(conn.executor(), insert_sql, unique_violation_ok)
+}
+
+async fn _insert_idempotent<'c, 'q, E>(conn: E, insert_sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>, unique_violation_ok: bool) -> Result<bool>
+where
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("insert_idempotent", insert_sql.sql());
+    let _sampled = _sample("insert_idempotent", insert_sql.sql());
+    let res = insert_sql.execute(conn).instrument(span).await;
+    match res {
+        Ok(_) => Ok(true),
+        Err(err) if unique_violation_ok && error::mysql_errno(&err) == Some(1062) => {
+            tracing::debug!("insert_idempotent: row exists, {}", error::summary(&err));
+            Ok(false)
+        }
         Err(err) => {
+            return Err(error::database(KIND, "insert_idempotent", err));
+        }
+    }
+}
+
+/// Runs `ddl` as a plain text statement, a `CREATE TABLE` failing with table exists (1050)
+/// or a `CREATE INDEX` with duplicate key name (1061) counts as done, so bootstrap code can
+/// run on every start.
+///
+/// This is for idempotent bootstrap only, it does not compare the existing table or index
+/// with `ddl`, changing the schema of a live database needs real migrations.
+pub fn ensure_ddl<'c, 'q>(conn: impl Conn<'c>, ddl: &'q str) -> impl Future<Output = Result<()>> + Send + Captures<'c> + Captures<'q> {
+    _ensure_ddl(conn.executor(), ddl)
+}
+
+async fn _ensure_ddl<'c, E>(conn: E, ddl: &str) -> Result<()>
+where
+    E: Executor<'c, Database = MySql>,
+{
+    let span = _span("ensure_ddl", ddl);
+    let _sampled = _sample("ensure_ddl", ddl);
+    let res = conn.execute(ddl).instrument(span).await;
+    match res {
+        Ok(_) => Ok(()),
+        Err(err) if matches!(error::mysql_errno(&err), Some(1050) | Some(1061)) => {
+            tracing::debug!("ensure_ddl: exists already, {}", error::summary(&err));
+            Ok(())
+        }
+        Err(err) => {
+            return Err(error::database(KIND, "ensure_ddl", err));
+        }
+    }
+}
+
+/// Runs `insert_sql`, then `select_by_id_sql` bound with its `last_insert_id`, to read back
+/// `DEFAULT` and generated columns since MySQL has no `RETURNING`.
+///
+/// Costs a second round trip plus `BEGIN` / `COMMIT`. Both run in one transaction, a savepoint
+/// when `conn` is in one already, so the row is read as inserted and an insert that can't be
+/// read back is rolled back with the error. `last_insert_id` is per connection, concurrent
+/// inserts on other connections never change it.
+///
+/// ```ignore
+/// let user = mysql::insert_then_fetch::<User>(&mut conn,
+///     mysql::query("INSERT INTO user (name) VALUES (?)").bind(name),
+///     "SELECT id, name, created_at FROM user WHERE id = ?").await?;
+/// ```
+pub async fn insert_then_fetch<'q, T>(conn: &mut MySqlConnection, insert_sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>, select_by_id_sql: &str) -> Result<T>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    let mut trans = match conn.begin().await {
+        Ok(trans) => trans,
+        Err(err) => {
+            return Err(error::database(KIND, "insert_then_fetch begin", err));
+        }
+    };
+    let (_, id) = exec(&mut *trans, insert_sql).await?;
+    let row = exec_one(&mut *trans, sqlx::query_as::<_, T>(select_by_id_sql).bind(id)).await?;
+    match trans.commit().await {
+        Ok(_) => Ok(row),
+        Err(err) => {
+            return Err(error::database(KIND, "insert_then_fetch commit", err));
+        }
+    }
+}
+
+/// One page of a result and where it sits in the whole, see `fetch_page`. Serializes with
+/// the `serde` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// rows of the whole result
+    pub total: u64,
+    /// from 1
+    pub page: u64,
+    pub page_size: u64,
+    pub total_pages: u64,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: u64, page: u64, page_size: u64) -> Self {
+        let total_pages = if page_size == 0 { 0 } else { total.div_ceil(page_size) };
+        Page { items, total, page, page_size, total_pages }
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.page < self.total_pages
+    }
+
+    pub fn has_prev(&self) -> bool {
+        self.page > 1
+    }
+}
+
+/// Page `page` of `sql` with `args`, counting from 1, with the total from a
+/// `SELECT COUNT(*)` over the same query.
+///
+/// `sql` gets `LIMIT ? OFFSET ?` appended, so it must not have a `LIMIT` of its own and needs
+/// an `ORDER BY` on a unique key for pages to be stable. The count and the page are two
+/// queries, rows written in between can make them disagree unless both run in a transaction.
+pub async fn fetch_page<T>(conn: &mut MySqlConnection, sql: &str, args: MySqlArguments, page: u64, page_size: u64) -> Result<Page<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    if page == 0 || page_size == 0 {
+        let err = format!("fetch_page failed: page {} and page_size {} must be at least 1", page, page_size);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    let count_sql = format!("SELECT COUNT(*) FROM ({}) AS _page", sql);
+    let span = _span("fetch_page", &count_sql);
+    let count_sample = _sample("fetch_page", &count_sql);
+    let res = sqlx::query_scalar_with::<_, i64, _>(&count_sql, args.clone()).fetch_one(&mut *conn).instrument(span).await;
+    drop(count_sample);
+    let total = match res {
+        Ok(total) => total as u64,
+        Err(err) => {
+            return Err(error::database(KIND, "fetch_page count", err));
+        }
+    };
+    let mut args = args;
+    sqlx::Arguments::add(&mut args, page_size);
+    sqlx::Arguments::add(&mut args, (page - 1).saturating_mul(page_size));
+    let page_sql = format!("{} LIMIT ? OFFSET ?", sql);
+    let span = _span("fetch_page", &page_sql);
+    let _sampled = _sample("fetch_page", &page_sql);
+    let res = sqlx::query_as_with::<_, T, _>(&page_sql, args).fetch_all(&mut *conn).instrument(span).await;
+    match res {
+        Ok(items) => Ok(Page::new(items, total, page, page_size)),
+        Err(err) => {
+            return Err(error::database_for::<T>(KIND, "fetch_page", err));
+        }
+    }
+}
+
+/// Runs the `SELECT` of `sql` with the `MAX_EXECUTION_TIME(ms)` optimizer hint, so the server
+/// itself aborts it after `ms` milliseconds, where a `tokio::time::timeout` leaves it running.
+/// An aborted query fails with `ERROR 3024`.
+///
+/// MySQL 5.7.8+ honors the hint for read-only `SELECT`s only and ignores it elsewhere, e.g. in
+/// a `SELECT ... FOR UPDATE` or in stored programs. `sql` not starting with `SELECT` is an
+/// error, a `WITH` query needs the hint written by hand after its `SELECT`.
+pub async fn exec_arr_max_time<T>(conn: &mut MySqlConnection, sql: &str, args: MySqlArguments, ms: u64) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    let sql = match crate::sql::with_optimizer_hint(sql, &format!("MAX_EXECUTION_TIME({})", ms)) {
+        Some(sql) if ms > 0 => sql,
+        _ => {
+            let err = format!("exec_arr_max_time failed: needs a SELECT and ms of at least 1, got {} ms for: {}", ms, crate::sql::truncate_for_log(sql));
             tracing::error!("{}", err);
-            return Err(Error::Database(format!("exec failed:{:?}", err)));
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+    };
+    let span = _span("exec_arr_max_time", &sql);
+    let _sampled = _sample("exec_arr_max_time", &sql);
+    let res = sqlx::query_as_with::<_, T, _>(&sql, args).fetch_all(&mut *conn).instrument(span).await;
+    match res {
+        Ok(arr) => Ok(arr),
+        Err(err) => {
+            return Err(error::database_for::<T>(KIND, "exec_arr_max_time", err));
         }
     }
 }
 
+/// Runs `EXPLAIN <sql>` and returns the raw plan rows, for ad-hoc diagnosis.
+///
+/// No arguments are bound, so write literals in place of `?` placeholders.
+pub async fn explain(conn: &mut MySqlConnection, sql: &str) -> Result<Vec<MySqlRow>> {
+    _explain(conn, "EXPLAIN", sql).await
+}
+
+/// Runs `EXPLAIN ANALYZE <sql>` (MySQL 8.0.18+). Unlike `explain` this really executes `sql`.
+pub async fn explain_analyze(conn: &mut MySqlConnection, sql: &str) -> Result<Vec<MySqlRow>> {
+    _explain(conn, "EXPLAIN ANALYZE", sql).await
+}
+
+async fn _explain(conn: &mut MySqlConnection, prefix: &str, sql: &str) -> Result<Vec<MySqlRow>> {
+    let sql = format!("{} {}", prefix, sql);
+    let res = sqlx::query(&sql).fetch_all(&mut *conn).await;
+    match res {
+        Ok(rows) => Ok(rows),
+        Err(err) => {
+            return Err(error::database(KIND, "explain", err));
+        }
+    }
+}
 
+/// The tables and views of the current database, `SHOW TABLES`.
+pub async fn show_tables(conn: &mut MySqlConnection) -> Result<Vec<String>> {
+    let res = sqlx::query("SHOW TABLES").fetch_all(&mut *conn).await;
+    let rows = match res {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(error::database(KIND, "show_tables", err));
+        }
+    };
+    // the column is named after the database, `Tables_in_<db>`
+    let res = rows.iter().map(|row| _show_str(row, 0usize)).collect::<std::result::Result<Vec<_>, _>>();
+    match res {
+        Ok(tables) => Ok(tables.into_iter().flatten().collect()),
+        Err(err) => {
+            return Err(error::database(KIND, "show_tables", err));
+        }
+    }
+}
+
+/// An output column of a statement, see `describe`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    /// the MySQL type name, e.g. `VARCHAR`, `BIGINT UNSIGNED` or `DATETIME`
+    pub type_name: String,
+    pub type_info: MySqlTypeInfo,
+    /// `None` when the server can't tell, e.g. for an expression
+    pub nullable: Option<bool>,
+}
+
+/// The output columns of `sql` without running it, from preparing the statement on the server.
+///
+/// `?` placeholders are fine and stay unbound, a statement without a result set, such as an
+/// `UPDATE`, has no columns. Types of expressions and placeholders in the select list are what
+/// MySQL infers at prepare time, e.g. a `SELECT ?` column is typed before any value is bound.
+pub async fn describe(conn: &mut MySqlConnection, sql: &str) -> Result<Vec<ColumnDef>> {
+    let res = (&mut *conn).describe(sql).await;
+    match res {
+        Ok(describe) => Ok(describe.columns().iter().enumerate().map(|(i, a)| ColumnDef {
+            name: a.name().to_string(),
+            type_name: a.type_info().name().to_string(),
+            type_info: a.type_info().clone(),
+            nullable: describe.nullable(i),
+        }).collect()),
+        Err(err) => {
+            return Err(error::database(KIND, "describe", err));
+        }
+    }
+}
+
+/// A row of `SHOW COLUMNS`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// the full type, e.g. `varchar(64)` or `int unsigned`
+    pub column_type: String,
+    pub nullable: bool,
+    /// `PRI`, `UNI`, `MUL` or empty
+    pub key: String,
+    pub default: Option<String>,
+    /// e.g. `auto_increment` or `DEFAULT_GENERATED`
+    pub extra: String,
+}
+
+/// The columns of `table` in definition order, `SHOW COLUMNS FROM table`.
+pub async fn show_columns(conn: &mut MySqlConnection, table: &str) -> Result<Vec<ColumnInfo>> {
+    _check_ident(table)?;
+    let res = sqlx::query(&format!("SHOW COLUMNS FROM `{}`", table)).fetch_all(&mut *conn).await;
+    let rows = match res {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(error::database(KIND, "show_columns", err));
+        }
+    };
+    let res = rows.iter().map(|row| Ok::<_, sqlx::Error>(ColumnInfo {
+        name: _show_str(row, "Field")?.unwrap_or_default(),
+        column_type: _show_str(row, "Type")?.unwrap_or_default(),
+        nullable: _show_str(row, "Null")?.as_deref() == Some("YES"),
+        key: _show_str(row, "Key")?.unwrap_or_default(),
+        default: _show_str(row, "Default")?,
+        extra: _show_str(row, "Extra")?.unwrap_or_default(),
+    })).collect::<std::result::Result<Vec<_>, _>>();
+    match res {
+        Ok(columns) => Ok(columns),
+        Err(err) => {
+            return Err(error::database(KIND, "show_columns", err));
+        }
+    }
+}
+
+/// A row of `SHOW INDEX`, one per column of each index.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IndexInfo {
+    /// `PRIMARY` for the primary key
+    pub key_name: String,
+    pub non_unique: bool,
+    /// position of the column in the index, from 1
+    pub seq_in_index: u32,
+    /// `None` for a functional key part
+    pub column_name: Option<String>,
+    /// estimated distinct values, `None` when not computed yet
+    pub cardinality: Option<u64>,
+    /// e.g. `BTREE` or `FULLTEXT`
+    pub index_type: String,
+    /// `false` for an invisible index, always `true` before MySQL 8
+    pub visible: bool,
+}
+
+/// The indexes of `table`, `SHOW INDEX FROM table`.
+pub async fn show_index(conn: &mut MySqlConnection, table: &str) -> Result<Vec<IndexInfo>> {
+    _check_ident(table)?;
+    let res = sqlx::query(&format!("SHOW INDEX FROM `{}`", table)).fetch_all(&mut *conn).await;
+    let rows = match res {
+        Ok(rows) => rows,
+        Err(err) => {
+            return Err(error::database(KIND, "show_index", err));
+        }
+    };
+    let res = rows.iter().map(|row| Ok::<_, sqlx::Error>(IndexInfo {
+        key_name: _show_str(row, "Key_name")?.unwrap_or_default(),
+        non_unique: _show_u64(row, "Non_unique")? == Some(1),
+        seq_in_index: _show_u64(row, "Seq_in_index")?.unwrap_or_default() as u32,
+        column_name: _show_str(row, "Column_name")?,
+        cardinality: _show_u64(row, "Cardinality")?,
+        index_type: _show_str(row, "Index_type")?.unwrap_or_default(),
+        visible: match row.try_column("Visible") {
+            Ok(_) => _show_str(row, "Visible")?.as_deref() != Some("NO"),
+            Err(_) => true,
+        },
+    })).collect::<std::result::Result<Vec<_>, _>>();
+    match res {
+        Ok(indexes) => Ok(indexes),
+        Err(err) => {
+            return Err(error::database(KIND, "show_index", err));
+        }
+    }
+}
+
+// SHOW columns are text or binary strings depending on the server version
+fn _show_str<I: sqlx::ColumnIndex<MySqlRow>>(row: &MySqlRow, index: I) -> std::result::Result<Option<String>, sqlx::Error> {
+    let bytes: Option<Vec<u8>> = row.try_get_unchecked(index)?;
+    Ok(bytes.map(|a| String::from_utf8_lossy(&a).into_owned()))
+}
+
+// SHOW numbers are signed or unsigned depending on the server version
+fn _show_u64<I: sqlx::ColumnIndex<MySqlRow> + Copy>(row: &MySqlRow, index: I) -> std::result::Result<Option<u64>, sqlx::Error> {
+    if let Ok(a) = row.try_get::<Option<u64>, _>(index) {
+        return Ok(a);
+    }
+    let a: Option<i64> = row.try_get(index)?;
+    Ok(a.map(|a| a.max(0) as u64))
+}
+
+/// The server side thread id of `conn`, the `PROCESSLIST_ID` of `performance_schema.threads`
+/// and the `Id` in the slow log.
+pub async fn connection_id(conn: &mut MySqlConnection) -> Result<u64> {
+    let res = sqlx::query_scalar::<_, u64>("SELECT CONNECTION_ID()").fetch_one(&mut *conn).await;
+    match res {
+        Ok(a) => Ok(a),
+        Err(err) => {
+            return Err(error::database(KIND, "connection_id", err));
+        }
+    }
+}
+
+/// The default schema of `conn`, `SELECT DATABASE()`, to check a connection points where
+/// it should. Fails when no schema is selected.
+pub async fn current_database(conn: &mut MySqlConnection) -> Result<String> {
+    let res = sqlx::query_scalar::<_, Option<String>>("SELECT DATABASE()").fetch_one(&mut *conn).await;
+    match res {
+        Ok(Some(a)) => Ok(a),
+        Ok(None) => {
+            let err = "current_database failed: no database selected".to_string();
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+        Err(err) => {
+            return Err(error::database(KIND, "current_database", err));
+        }
+    }
+}
+
+/// Clears the session state a previous user of a pooled connection may have left: user
+/// variables are set to `NULL`, which reads the same as never set, and `sql_mode` and
+/// `time_zone` go back to their values right after connecting. `@app_name` is kept.
+///
+/// MySQL's `RESET CONNECTION` is `COM_RESET_CONNECTION`, a protocol command (5.7.3+) and not a
+/// statement, sqlx 0.7 can't send it, so this resets what SQL can reach. Temporary tables,
+/// prepared `PREPARE` statements and other session variables are not reset, use
+/// `PoolConnection::close` for a connection that may hold those. Reading the user variables
+/// needs `performance_schema`. It costs two or three round trips, see `MYSQL.RESET_ON_RELEASE`
+/// to run it on every connection going back to the pool.
+pub async fn reset_session(conn: &mut MySqlConnection) -> Result<()> {
+    let res = sqlx::query_scalar::<_, String>(
+        "SELECT VARIABLE_NAME FROM performance_schema.user_variables_by_thread WHERE THREAD_ID = (SELECT THREAD_ID FROM performance_schema.threads WHERE PROCESSLIST_ID = CONNECTION_ID())"
+    ).fetch_all(&mut *conn).await;
+    let names = match res {
+        Ok(names) => names,
+        Err(err) => {
+            return Err(error::database(KIND, "reset_session", err));
+        }
+    };
+    let clears: Vec<String> = names.iter()
+        .filter(|a| a.as_str() != "app_name" && !a.starts_with("_qx_"))
+        .map(|a| format!("@`{}` = NULL", a.replace('`', "``")))
+        .collect();
+    // the pool keeps the state after connecting in @_qx_*, a connection of its own has none
+    let mut sql = "SET SESSION sql_mode = COALESCE(@_qx_sql_mode, @@SESSION.sql_mode), time_zone = COALESCE(@_qx_time_zone, @@SESSION.time_zone)".to_string();
+    for clear in clears.iter() {
+        sql.push_str(", ");
+        sql.push_str(clear);
+    }
+    if let Err(err) = (&mut *conn).execute(sql.as_str()).await {
+        return Err(error::database(KIND, "reset_session", err));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Charset {
+    pub character_set: String,
+    pub collation: String,
+}
+
+/// The `@@character_set_connection` and `@@collation_connection` of `conn`, which strings
+/// sent and returned on it are converted to.
+pub async fn connection_charset(conn: &mut MySqlConnection) -> Result<Charset> {
+    let res = sqlx::query_as::<_, (String, String)>("SELECT @@character_set_connection, @@collation_connection").fetch_one(&mut *conn).await;
+    match res {
+        Ok((character_set, collation)) => Ok(Charset { character_set, collation }),
+        Err(err) => {
+            return Err(error::database(KIND, "connection_charset", err));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+    pub mariadb: bool,
+}
+
+impl ServerVersion {
+    /// Parses `SELECT VERSION()` output like `8.0.36-0ubuntu0.22.04.1` or `10.11.6-MariaDB`.
+    pub fn parse(version: &str) -> Result<Self> {
+        let numbers = version.split(|c: char| !c.is_ascii_digit() && c != '.').next().unwrap_or("");
+        let mut parts = numbers.split('.').map(|a| a.parse::<u16>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => Ok(ServerVersion {
+                major,
+                minor,
+                patch,
+                mariadb: version.contains("MariaDB"),
+            }),
+            _ => {
+                let err = format!("invalid server version: {:?}", version);
+                tracing::error!("{}", err);
+                return Err(DbError::new(ErrorKind::Other, err));
+            }
+        }
+    }
+
+    pub fn at_least(&self, major: u16, minor: u16, patch: u16) -> bool {
+        (self.major, self.minor, self.patch) >= (major, minor, patch)
+    }
+}
+
+pub async fn server_version(conn: &mut MySqlConnection) -> Result<ServerVersion> {
+    let res = sqlx::query_scalar::<_, String>("SELECT VERSION()").fetch_one(&mut *conn).await;
+    match res {
+        Ok(a) => ServerVersion::parse(&a),
+        Err(err) => {
+            return Err(error::database(KIND, "server_version", err));
+        }
+    }
+}
+
+/// Builds `INSERT INTO table (columns) VALUES (?, ...) ON DUPLICATE KEY UPDATE ...` for `version`.
+///
+/// MySQL 8.0.20 deprecated `VALUES(col)` in the update list and warns on every use, so servers
+/// with row aliases, 8.0.19 on, get the aliased row (`VALUES (...) AS new ... col = new.col`).
+/// Older servers and MariaDB, which has no row alias, get the `col = VALUES(col)` form.
+pub fn upsert_sql(table: &str, columns: &[&str], update_columns: &[&str], version: &ServerVersion) -> Result<String> {
+    _check_ident(table)?;
+    for column in columns.iter().chain(update_columns) {
+        _check_ident(column)?;
+    }
+    if columns.is_empty() || update_columns.is_empty() {
+        return Err(DbError::new(ErrorKind::Other, "upsert_sql failed: columns and update_columns must not be empty".to_string()));
+    }
+    let alias = !version.mariadb && version.at_least(8, 0, 19);
+    let names = columns.iter().map(|a| format!("`{}`", a)).collect::<Vec<_>>().join(", ");
+    let placeholders = vec!["?"; columns.len()].join(", ");
+    let updates = update_columns.iter().map(|a| {
+        if alias {
+            format!("`{}` = new.`{}`", a, a)
+        } else {
+            format!("`{}` = VALUES(`{}`)", a, a)
+        }
+    }).collect::<Vec<_>>().join(", ");
+    let row_alias = if alias { " AS new" } else { "" };
+    Ok(format!("INSERT INTO `{}` ({}) VALUES ({}){} ON DUPLICATE KEY UPDATE {}", table, names, placeholders, row_alias, updates))
+}
+
+/// The shared lock clause for `version`, `FOR SHARE` from MySQL 8.0.1, `LOCK IN SHARE MODE`
+/// before and on MariaDB, see `tx::select_for_share`.
+pub fn share_lock_clause(version: &ServerVersion) -> &'static str {
+    if !version.mariadb && version.at_least(8, 0, 1) {
+        "FOR SHARE"
+    } else {
+        "LOCK IN SHARE MODE"
+    }
+}
+
+/// What a single row `INSERT ... ON DUPLICATE KEY UPDATE` did, from its affected rows.
+///
+/// There is no "unchanged" outcome: sqlx connects with the `CLIENT_FOUND_ROWS` flag, which
+/// makes a row the update leaves as it was report 1 like a new row, and neither `ROW_COUNT()`
+/// nor the insert id tell the two apart reliably. So `Inserted` means inserted or left
+/// unchanged. When that matters, update a column that always changes, like a version counter
+/// or `updated_at = NOW(6)`, then 1 is always a new row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpsertOutcome {
+    Inserted,
+    Updated,
+}
+
+impl UpsertOutcome {
+    /// 1 for a new row, or an unchanged one, and 2 for an updated one, as counted with
+    /// `CLIENT_FOUND_ROWS`. Anything else is an error, like 0 from a client without the flag.
+    pub fn from_rows_affected(rows_affected: u64) -> Result<Self> {
+        match rows_affected {
+            1 => Ok(UpsertOutcome::Inserted),
+            2 => Ok(UpsertOutcome::Updated),
+            _ => {
+                let err = format!("upsert affected {} rows, expected a single row upsert", rows_affected);
+                tracing::error!("{}", err);
+                return Err(DbError::new(ErrorKind::Other, err));
+            }
+        }
+    }
+}
+
+/// Runs a single row upsert, e.g. built by `upsert_sql` and bound, see `UpsertOutcome`.
+pub fn exec_upsert<'c, 'q>(conn: impl Conn<'c>, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> impl Future<Output = Result<UpsertOutcome>> + Send + Captures<'c> + Captures<'q> {
+    _exec_upsert(conn.executor(), sql)
+}
+
+async fn _exec_upsert<'c, 'q, E>(conn: E, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<UpsertOutcome>
+where
+    E: Executor<'c, Database = MySql>,
+{
+    let (rows_affected, _) = _exec(conn, sql).await?;
+    UpsertOutcome::from_rows_affected(rows_affected)
+}
+
+/// Runs an optimistic locking update, `expected_version` bound as its last `?`, and returns
+/// whether it won: `false` when no row matched because another update bumped the version first.
+///
+/// ```ignore
+/// let sql = mysql::query("UPDATE account SET balance = ?, version = version + 1 WHERE id = ? AND version = ?").bind(balance).bind(id);
+/// if !mysql::update_versioned(&mut conn, sql, account.version).await? {
+///     // reload the row and apply the change again, or report the conflict
+/// }
+/// ```
+///
+/// Affected rows decide, so the `WHERE` must match a single row by key, more than one is an
+/// error returned after the update ran, run it in a transaction to roll that back. sqlx always
+/// connects with `CLIENT_FOUND_ROWS`, counting the rows matched rather than changed, the count
+/// optimistic locking needs: a clean connection without it reports 0 for an update that happens
+/// to write the values already there, taken for a lost race. Bump the version in every update
+/// anyway, so a row is never left as it was.
+pub fn update_versioned<'c, 'q>(conn: impl Conn<'c>, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>, expected_version: i64) -> impl Future<Output = Result<bool>> + Send + Captures<'c> + Captures<'q> {
+    _update_versioned(conn.executor(), sql.bind(expected_version))
+}
+
+async fn _update_versioned<'c, 'q, E>(conn: E, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<bool>
+where
+    E: Executor<'c, Database = MySql>,
+{
+    let (rows_affected, _) = _exec(conn, sql).await?;
+    match rows_affected {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => {
+            let err = format!("update_versioned affected {} rows, expected a single row update", rows_affected);
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+    }
+}
+
+pub async fn max_allowed_packet(conn: &mut MySqlConnection) -> Result<u64> {
+    let res = sqlx::query_scalar::<_, u64>("SELECT CAST(@@max_allowed_packet AS UNSIGNED)").fetch_one(&mut *conn).await;
+    match res {
+        Ok(a) => Ok(a),
+        Err(err) => {
+            return Err(error::database(KIND, "max_allowed_packet", err));
+        }
+    }
+}
+
+/// Fails with an actionable error when a value of `len` bytes can't be sent in one packet.
+///
+/// MySQL sends every bound `BLOB` / `LONGTEXT` inside a single packet, the whole value is
+/// buffered and there is no streaming. Only the value itself is checked, the rest of the
+/// statement needs to fit as well.
+pub fn check_packet_len(len: usize, max_allowed_packet: u64) -> Result<()> {
+    if len as u64 > max_allowed_packet {
+        let err = format!("value of {} bytes exceeds max_allowed_packet of {} bytes, raise the server max_allowed_packet or split the value", len, max_allowed_packet);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::PacketTooLarge, err));
+    }
+    Ok(())
+}
+
+
+#[allow(mismatched_lifetime_syntaxes)]
 pub fn query<'q>(sql: &'q str) -> Query<'q, MySql, <MySql as HasArguments<'_>>::Arguments>
 {
     sqlx::query(sql)
@@ -80,20 +1369,182 @@ where
     sqlx::query_as::<_, T>(sql)
 }
 
+pub fn query_scalar<'q, T>(sql: &'q str) -> QueryScalar<'q, MySql, T, MySqlArguments>
+where
+    (T,): for<'r> FromRow<'r, MySqlRow>,
+{
+    sqlx::query_scalar::<_, T>(sql)
+}
+
+#[allow(clippy::multiple_bound_locations)]
 pub async fn query_as_with<'q, T, A: 'q>(connect: &mut MySqlConnection, sql: &'q str, args: A) -> Result<Vec<T>> 
 where
     T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
     A: IntoArguments<'q, MySql>,
 {
+    let span = _span("query_as_with", sql);
+    let _sampled = _sample("query_as_with", sql);
     let res = sqlx::query_as_with::<_, T, A>(sql, args)
-        .fetch_all(&mut *connect).await;
+        .fetch_all(&mut *connect).instrument(span).await;
     match res {
         Ok(users) => {
             Ok(users)
         },
         Err(err) => {
-            tracing::error!("{}", err);
-            return Err(Error::Database(format!("query_as_with failed:{:?}", err)));
+            return Err(error::database_for::<T>(KIND, "query_as_with", err));
+        }
+    }
+}
+
+/// Runs `sql` once per argument set on the same connection, one result vec per input.
+///
+/// The queries run one after another, sqlx has no pipelining for MySQL, but the
+/// connection and the prepared statement are reused for every set.
+pub async fn query_as_with_batch<'q, T, A>(connect: &mut MySqlConnection, sql: &'q str, args_list: Vec<A>) -> Result<Vec<Vec<T>>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    A: 'q + IntoArguments<'q, MySql>,
+{
+    let mut results = Vec::with_capacity(args_list.len());
+    for args in args_list {
+        let span = _span("query_as_with_batch", sql);
+        let _sampled = _sample("query_as_with_batch", sql);
+        let res = sqlx::query_as_with::<_, T, A>(sql, args)
+            .fetch_all(&mut *connect).instrument(span).await;
+        match res {
+            Ok(arr) => results.push(arr),
+            Err(err) => {
+                return Err(error::database_for::<T>(KIND, "query_as_with_batch", err));
+            }
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _order_by(requested: &str, allowed: &[&str], dir: SortDir) -> Result<String> {
+        let mut builder = QueryBuilder::<MySql>::new("SELECT * FROM t");
+        order_by(&mut builder, requested, allowed, dir)?;
+        Ok(builder.into_sql())
+    }
+
+    #[test]
+    fn order_by_appends_allowed_columns() {
+        assert_eq!(_order_by("name", &["id", "name"], SortDir::Asc).unwrap(), "SELECT * FROM t ORDER BY `name` ASC");
+        assert_eq!(_order_by("id", &["id", "name"], SortDir::Desc).unwrap(), "SELECT * FROM t ORDER BY `id` DESC");
+    }
+
+    #[test]
+    fn order_by_rejects_other_columns() {
+        assert!(_order_by("email", &["id", "name"], SortDir::Asc).is_err());
+        assert!(_order_by("NAME", &["id", "name"], SortDir::Asc).is_err());
+        assert!(_order_by("name; DROP TABLE t", &["id", "name"], SortDir::Asc).is_err());
+        assert!(_order_by("id` DESC, (SELECT 1) #", &["id", "name"], SortDir::Asc).is_err());
+        // an unsafe name fails even when allowed by mistake
+        assert!(_order_by("id`; --", &["id`; --"], SortDir::Asc).is_err());
+    }
+
+    fn _in_chunk_sizes(len: usize, max_in_list: usize) -> Vec<usize> {
+        let sql = "SELECT id FROM t WHERE id IN (?) ORDER BY id";
+        let in_list = crate::sql::in_list_placeholder(sql).unwrap();
+        _in_chunks(sql, in_list, len, max_in_list).into_iter().map(|(range, sql)| {
+            assert!(sql.starts_with("SELECT id FROM t WHERE id IN (?") && sql.ends_with("?) ORDER BY id"), "{}", sql);
+            let placeholders = sql.matches('?').count();
+            assert_eq!(placeholders, range.len());
+            placeholders
+        }).collect()
+    }
+
+    #[test]
+    fn fetch_in_splits_into_chunks_of_max_in_list() {
+        assert_eq!(_in_chunk_sizes(20000, 1000), vec![1000; 20]);
+        assert_eq!(_in_chunk_sizes(2500, 1000), [1000, 1000, 500]);
+        assert_eq!(_in_chunk_sizes(3, 1000), [3]);
+        assert_eq!(_in_chunk_sizes(20000, 0), [20000]);
+    }
+
+    #[test]
+    fn server_version_parses_mysql_and_mariadb() {
+        assert_eq!(ServerVersion::parse("5.7.44-log").unwrap(), ServerVersion { major: 5, minor: 7, patch: 44, mariadb: false });
+        assert_eq!(ServerVersion::parse("8.0.36-0ubuntu0.22.04.1").unwrap(), ServerVersion { major: 8, minor: 0, patch: 36, mariadb: false });
+        assert_eq!(ServerVersion::parse("10.11.6-MariaDB").unwrap(), ServerVersion { major: 10, minor: 11, patch: 6, mariadb: true });
+        assert!(ServerVersion::parse("8.0").is_err());
+        assert!(ServerVersion::parse("").is_err());
+    }
+
+    #[test]
+    fn upsert_sql_aliases_the_row_from_8_0_19() {
+        let version = ServerVersion::parse("8.0.19").unwrap();
+        assert_eq!(
+            upsert_sql("user", &["id", "name"], &["name"], &version).unwrap(),
+            "INSERT INTO `user` (`id`, `name`) VALUES (?, ?) AS new ON DUPLICATE KEY UPDATE `name` = new.`name`",
+        );
+        assert!(upsert_sql("user", &["id", "name"], &["name"], &ServerVersion::parse("8.4.0").unwrap()).unwrap().contains(" AS new "));
+    }
+
+    #[test]
+    fn upsert_sql_uses_values_before_8_0_19_and_on_mariadb() {
+        let legacy = "INSERT INTO `user` (`id`, `name`) VALUES (?, ?) ON DUPLICATE KEY UPDATE `name` = VALUES(`name`)";
+        for version in ["5.7.44-log", "8.0.18", "10.11.6-MariaDB", "11.4.2-MariaDB-log"] {
+            let version = ServerVersion::parse(version).unwrap();
+            assert_eq!(upsert_sql("user", &["id", "name"], &["name"], &version).unwrap(), legacy);
         }
     }
+
+    #[test]
+    fn upsert_sql_checks_names() {
+        let version = ServerVersion::parse("8.0.36").unwrap();
+        assert!(upsert_sql("user`; DROP TABLE t; --", &["id"], &["id"], &version).is_err());
+        assert!(upsert_sql("user", &["id"], &[], &version).is_err());
+    }
+
+    #[test]
+    fn upsert_outcome_from_rows_affected() {
+        assert_eq!(UpsertOutcome::from_rows_affected(1).unwrap(), UpsertOutcome::Inserted);
+        assert_eq!(UpsertOutcome::from_rows_affected(2).unwrap(), UpsertOutcome::Updated);
+        assert!(UpsertOutcome::from_rows_affected(0).is_err());
+        assert!(UpsertOutcome::from_rows_affected(4).is_err());
+    }
+
+    #[test]
+    fn sort_dir_parses_asc_and_desc_only() {
+        assert_eq!("asc".parse::<SortDir>().unwrap(), SortDir::Asc);
+        assert_eq!("DESC".parse::<SortDir>().unwrap(), SortDir::Desc);
+        assert!("DESC; DROP TABLE t".parse::<SortDir>().is_err());
+        assert!("".parse::<SortDir>().is_err());
+    }
+
+    #[test]
+    fn safe_table_quotes_valid_names() {
+        assert_eq!(safe_table("events_2024_05").unwrap(), "`events_2024_05`");
+        assert_eq!(safe_table("Orders").unwrap(), "`Orders`");
+        assert_eq!(safe_table(&"t".repeat(64)).unwrap(), format!("`{}`", "t".repeat(64)));
+    }
+
+    #[test]
+    fn safe_table_rejects_reserved_words_in_any_case() {
+        assert!(safe_table("select").is_err());
+        assert!(safe_table("Order").is_err());
+        assert!(safe_table("gRoUp").is_err());
+        assert!(safe_table("orders").is_ok());
+    }
+
+    #[test]
+    fn safe_table_rejects_backticks_and_other_chars() {
+        assert!(safe_table("a`b").is_err());
+        assert!(safe_table("`users`").is_err());
+        assert!(safe_table("users; DROP TABLE t").is_err());
+        assert!(safe_table("db.users").is_err());
+        assert!(safe_table("tablé").is_err());
+    }
+
+    #[test]
+    fn safe_table_rejects_empty_digits_and_long_names() {
+        assert!(safe_table("").is_err());
+        assert!(safe_table("2024").is_err());
+        assert!(safe_table(&"t".repeat(65)).is_err());
+    }
 }
@@ -1,144 +1,1809 @@
 #![allow(unused)]
 
 use once_cell::sync::Lazy;
-use sqlx::Acquire;
+use sqlx::{Acquire, Connection, Executor};
 use sqlx::{
     self,
-    mysql::{MySqlConnectOptions, MySqlPoolOptions},
+    mysql::{MySqlArguments, MySqlConnectOptions, MySqlPoolOptions, MySqlRow},
     pool::PoolConnection,
-    MySql, Pool, Transaction,
+    FromRow, IntoArguments, MySql, MySqlConnection, Pool, Transaction,
 };
-use tokio::sync::Mutex;
-use std::{collections::HashMap, str::FromStr};
-use tracing::{self, instrument};
+use tokio::sync::{mpsc, Mutex, OnceCell};
+use std::{collections::HashMap, future::Future, str::FromStr, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}, time::{Duration, Instant}};
+use tracing::{self, instrument, Instrument, Span};
 
-use qx_rs_server::err::{Error, Result};
 use qx_rs_server::env::{self, DEFAULT};
+use qx_rs_server::util::val;
 
+use crate::error::{self, DatabaseKind, DbError, ErrorKind, Result};
+use crate::mysql::{self, Charset, ServerVersion};
+use crate::read_only::ReadOnlyConn;
+use crate::tx::TransactionGuard;
+
+const KIND: DatabaseKind = DatabaseKind::MySql;
 
 static POOLS: Lazy<Mutex<HashMap<&'static str, Pool<MySql>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// MYSQL.MAX_ALLOWED_PACKET if configured, else asked from the server on first use
+static PACKET_LIMITS: Lazy<Mutex<HashMap<&'static str, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// detected at setup, see charset
+static CHARSETS: Lazy<Mutex<HashMap<&'static str, Charset>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static SERVER_VERSIONS: Lazy<Mutex<HashMap<&'static str, ServerVersion>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+struct Replica {
+    url: String,
+    weight: u32,
+    pool: Pool<MySql>,
+    failed_at: std::sync::Mutex<Option<Instant>>,
+}
+struct Replicas {
+    list: Vec<Replica>,
+    cooldown: Duration,
+}
+static REPLICAS: Lazy<Mutex<HashMap<&'static str, Arc<Replicas>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static RNG: Lazy<AtomicU64> = Lazy::new(|| {
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|a| a.as_nanos() as u64).unwrap_or(0);
+    AtomicU64::new(nanos)
+});
+// debug builds: open transactions per task that began them, see _check_self_deadlock
+#[cfg(debug_assertions)]
+static TX_TASKS: Lazy<std::sync::Mutex<HashMap<tokio::task::Id, usize>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// connections the tx helpers hold in a transaction, per task and database, see _check_held_tx
+static HELD_TXS: Lazy<std::sync::Mutex<HashMap<(tokio::task::Id, &'static str), usize>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// MYSQL.SELF_DEADLOCK of the databases that set it to warn or off
+static SELF_DEADLOCK_MODES: Lazy<std::sync::Mutex<HashMap<&'static str, SelfDeadlockMode>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// circuit breakers of the databases with MYSQL.CIRCUIT_FAILURES set
+static CIRCUITS: Lazy<std::sync::Mutex<HashMap<&'static str, Circuit>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// queues of spawn_exec, each drained by one task
+type BackgroundWrite = (String, MySqlArguments);
+static BACKGROUND: Lazy<std::sync::Mutex<HashMap<&'static str, mpsc::Sender<BackgroundWrite>>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+const DEFAULT_BACKGROUND_QUEUE: usize = 1000;
+// how long a background write waits for a connection, and the longest pause between attempts
+const BACKGROUND_RETRY: Duration = Duration::from_secs(60);
+const BACKGROUND_MAX_BACKOFF: Duration = Duration::from_secs(5);
+// tasks waiting in _get_conn for a connection, see acquire_wait_depth
+static ACQUIRE_WAITS: Lazy<std::sync::Mutex<HashMap<&'static str, Arc<AtomicUsize>>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// MYSQL.ACQUIRE_RETRIES of the databases that set it
+static ACQUIRE_RETRIES: Lazy<std::sync::Mutex<HashMap<&'static str, u32>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// MYSQL.SLOW_ACQUIRE_MS of the databases that set it
+static SLOW_ACQUIRES: Lazy<std::sync::Mutex<HashMap<&'static str, Duration>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+const DEFAULT_SLOW_ACQUIRE: Duration = Duration::from_millis(100);
+// moving average of the acquire wait of each database, see _record_acquire_wait
+static ACQUIRE_AVGS: Lazy<std::sync::Mutex<HashMap<&'static str, AcquireAvg>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// MYSQL.SLOW_ACQUIRE_AVG_MS and friends of the databases that set it
+static SLOW_ACQUIRE_ALARMS: Lazy<std::sync::Mutex<HashMap<&'static str, SlowAcquireAlarm>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+// weight of the latest acquire in the moving average
+const ACQUIRE_AVG_ALPHA: f64 = 0.1;
+// peak demand of each database over the last minutes, see suggested_max_connections
+static PEAKS: Lazy<std::sync::Mutex<HashMap<&'static str, PeakWindow>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+const PEAK_WINDOW_MINUTES: usize = 15;
+const PEAK_LOG_INTERVAL: Duration = Duration::from_secs(300);
+// set by setup_with_options, kept so reconnect builds the same options
+type Configure = Arc<dyn Fn(MySqlConnectOptions) -> MySqlConnectOptions + Send + Sync>;
+static CONFIGURES: Lazy<Mutex<HashMap<&'static str, Configure>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// prepared on every new connection, see register_warm_queries
+static WARM_QUERIES: Lazy<std::sync::Mutex<Vec<String>>> = Lazy::new(|| std::sync::Mutex::new(Vec::new()));
+// concurrent setup calls of one database await a single initialization, a failed one can be retried
+type SetupCell = Arc<OnceCell<()>>;
+static SETUPS: Lazy<Mutex<HashMap<&'static str, SetupCell>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
-#[instrument]
+#[cfg_attr(feature = "tracing-instrument", instrument)]
 pub async fn get_conn() -> Result<PoolConnection<MySql>> {
     _get_conn(DEFAULT).await
 }
 
-#[instrument]
+#[cfg_attr(feature = "tracing-instrument", instrument)]
 pub async fn get_conn_from_database(
     which_database: &'static str,
 ) -> Result<PoolConnection<MySql>> {
     _get_conn(which_database).await
 }
 
-#[instrument]
+/// `get_conn` that gives up at `deadline`, e.g. the deadline of the request, so no connection
+/// is taken for a client that is gone already. Fails at once when `deadline` has passed.
+///
+/// The wait is the shorter of the time left and the pool `acquire_timeout`, whichever ends
+/// first decides the error: `acquire deadline passed` for the deadline, the usual pool timeout
+/// otherwise. `MYSQL.ACQUIRE_RETRIES` retries stop at the deadline too.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_conn_deadline(which_database: &'static str, deadline: Instant) -> Result<PoolConnection<MySql>> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        let err = format!("database '{}' acquire deadline passed before acquiring", which_database);
+        tracing::warn!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    match tokio::time::timeout(remaining, _get_conn(which_database)).await {
+        Ok(res) => res,
+        Err(_) => {
+            let err = format!("database '{}' acquire deadline passed after waiting {:?}", which_database, remaining);
+            tracing::warn!("{}", err);
+            return Err(DbError::new(ErrorKind::PoolExhausted, err));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// always acquires, like `get_conn`
+    High,
+    /// shed first when the process is short of connections
+    Low,
+}
+
+/// `get_conn` that sheds `Low` priority work under connection pressure.
+///
+/// With `MYSQL.LOW_PRIORITY_CAP` set, a `Low` acquire fails at once, see `error::is_overloaded`,
+/// while the connections in use across all pools, replicas left out, are at or above the cap.
+/// `High` acquires always go on to the pool. The cap is advisory: the count is taken without a
+/// lock, so concurrent acquires can pass it by a few, and it only covers acquires made here.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_conn_priority(which_database: &'static str, priority: Priority) -> Result<PoolConnection<MySql>> {
+    if priority == Priority::Low {
+        if let Some(cap) = _opt_val::<u32>("MYSQL.LOW_PRIORITY_CAP")? {
+            let in_use: u32 = POOLS.lock().await.values().map(|pool| pool.size().saturating_sub(pool.num_idle() as u32)).sum();
+            if in_use >= cap {
+                let err = format!("database '{}' overloaded, {} connections in use of a low priority cap of {}, low priority acquire shed", which_database, in_use, cap);
+                tracing::warn!("{}", err);
+                return Err(DbError::new(ErrorKind::Overloaded, err));
+            }
+        }
+    }
+    _get_conn(which_database).await
+}
+
+/// Acquires a connection together with a `db.connection` span, to correlate app logs with
+/// `performance_schema` and the slow log.
+///
+/// Run the work on the connection inside the span (`.instrument(span)`) and every `exec*`
+/// event gets its fields. sqlx doesn't expose which physical connection the pool hands out,
+/// so its id can't be cached per connection and the acquire doesn't read it. The span has an
+/// empty `connection_id` field, record it where the correlation is worth the round trip:
+///
+/// ```ignore
+/// let (mut conn, span) = pool::get_conn_traced(DEFAULT).await?;
+/// span.record("connection_id", mysql::connection_id(&mut conn).await?);
+/// ```
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_conn_traced(which_database: &'static str) -> Result<(PoolConnection<MySql>, Span)> {
+    let conn = _get_conn(which_database).await?;
+    let span = tracing::info_span!("db.connection", db = which_database, connection_id = tracing::field::Empty);
+    Ok((conn, span))
+}
+
+/// A connection of `which_database` that rejects everything but reads, see `ReadOnlyConn`.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_read_only_conn(which_database: &'static str) -> Result<ReadOnlyConn> {
+    let conn = _get_conn(which_database).await?;
+    Ok(ReadOnlyConn::new(conn))
+}
+
+/// A connection of a read replica of the default database, see `get_read_conn_from_database`.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_read_conn() -> Result<PoolConnection<MySql>> {
+    _get_read_conn(DEFAULT).await
+}
+
+/// A connection of a read replica of `which_database`, falling back to the primary when
+/// no replica is configured in `MYSQL.READ_URLS` or none is available.
+///
+/// A replica is picked at random with a probability proportional to its `MYSQL.READ_WEIGHTS`
+/// weight. A replica whose acquire or `check_read_replicas` ping failed is skipped for
+/// `MYSQL.READ_COOLDOWN_SECS` (default 30), and on an acquire failure the next replica is
+/// picked from the rest. `seed_read_replicas` makes the picks reproducible.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_read_conn_from_database(which_database: &'static str) -> Result<PoolConnection<MySql>> {
+    _get_read_conn(which_database).await
+}
+
+/// Pings every read replica of `which_database`, failed ones start their cooldown.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn check_read_replicas(which_database: &'static str) -> Result<()> {
+    let replicas = REPLICAS.lock().await.get(which_database).cloned();
+    if let Some(replicas) = replicas {
+        for replica in replicas.list.iter() {
+            let res = match replica.pool.acquire().await {
+                Ok(mut conn) => conn.ping().await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                tracing::warn!("read replica {} failed health check: {}", replica.url, error::summary(&err));
+                *replica.failed_at.lock().unwrap() = Some(Instant::now());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Seeds the replica picking of `get_read_conn`, for reproducible tests.
+pub fn seed_read_replicas(seed: u64) {
+    RNG.store(seed, Ordering::Relaxed);
+}
+
+/// Adds statements to prepare on every new connection of every database, so the first query of
+/// a fresh connection finds them in the sqlx statement cache instead of paying the prepare.
+///
+/// Register before `setup`, connections opened earlier are not warmed. The statements are
+/// parsed by the server when a connection opens, so they must be valid then: a failing one
+/// fails the connection and with it the acquire. Keep the list below the statement cache
+/// capacity (100 by default), or the cache evicts the first ones again.
+pub fn register_warm_queries(sqls: &[&str]) {
+    let mut warm_queries = WARM_QUERIES.lock().unwrap();
+    for sql in sqls {
+        if !warm_queries.iter().any(|a| a == sql) {
+            warm_queries.push(sql.to_string());
+        }
+    }
+}
+
+/// Names of all databases that have been set up, sorted.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn list_databases() -> Vec<&'static str> {
+    let map = POOLS.lock().await;
+    let mut names: Vec<&'static str> = map.keys().copied().collect();
+    names.sort();
+    names
+}
+
+/// Runs `f` with a connection of every database in `list_databases()` order, e.g. to apply
+/// one migration to all shards.
+///
+/// Every database is visited even if some fail, the returned error names all failed ones.
+/// The connection is handed over by value and goes back to the pool when `f` drops it.
+#[cfg_attr(feature = "tracing-instrument", instrument(skip(f)))]
+pub async fn for_each_database<F, Fut>(f: F) -> Result<()>
+where
+    F: Fn(&'static str, PoolConnection<MySql>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut failed = Vec::new();
+    for which_database in list_databases().await {
+        let res = match _get_conn(which_database).await {
+            Ok(conn) => f(which_database, conn).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            tracing::error!("for_each_database {} failed: {}", which_database, err);
+            failed.push(format!("{}: {}", which_database, err));
+        }
+    }
+    if !failed.is_empty() {
+        return Err(DbError::new(ErrorKind::Other, format!("for_each_database failed for {}", failed.join("; "))));
+    }
+    Ok(())
+}
+
+/// Runs `sql` on every database of `databases` concurrently, with the arguments `args_fn`
+/// builds for each, and concatenates the rows in the order of `databases`, for scatter-gather
+/// reads over shards.
+///
+/// Rows keep the order of each shard but there is no order across shards, an `ORDER BY` or
+/// `LIMIT` applies per shard, sort and cut the merged rows again where it matters. Fails if
+/// any shard fails, the error names all failed ones.
+#[cfg_attr(feature = "tracing-instrument", instrument(skip(args_fn)))]
+pub async fn fan_out_query_as<'q, T, A, F>(databases: &[&'static str], sql: &'q str, args_fn: F) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+    A: 'q + IntoArguments<'q, MySql>,
+    F: Fn(&'static str) -> A,
+{
+    let queries = databases.iter().map(|which_database| {
+        let args = args_fn(which_database);
+        async move {
+            let mut conn = _get_conn(which_database).await?;
+            mysql::query_as_with::<T, A>(&mut conn, sql, args).await
+        }
+    });
+    let results = futures::future::join_all(queries).await;
+    let mut rows = Vec::new();
+    let mut failed = Vec::new();
+    for (which_database, res) in databases.iter().zip(results) {
+        match res {
+            Ok(arr) => rows.extend(arr),
+            Err(err) => {
+                tracing::error!("fan_out_query_as {} failed: {}", which_database, err);
+                failed.push(format!("{}: {}", which_database, err));
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(DbError::new(ErrorKind::Other, format!("fan_out_query_as failed for {}", failed.join("; "))));
+    }
+    Ok(rows)
+}
+
+/// Queues `sql` with `args` to run on `which_database` in the background, for low-criticality
+/// writes like audit events and telemetry that shouldn't hold up the request.
+///
+/// One task per database runs the queue in order on a single connection, so background
+/// writes never take more than one connection from the pool. The queue holds
+/// `MYSQL.BACKGROUND_QUEUE` writes, 1000 by default, writes arriving while it is full are
+/// dropped with a warning. When no connection can be acquired the task waits and tries again,
+/// backing off up to 5s between attempts, and drops the write after a minute, writes arriving
+/// meanwhile fill the queue. Failed statements are logged and not retried, a write runs at most
+/// once and is lost if the process exits before it ran. A task that ended with the runtime it
+/// ran on is replaced by the next call. Must be called inside the tokio runtime.
+pub fn spawn_exec(which_database: &'static str, sql: String, args: MySqlArguments) {
+    let sender = {
+        let mut queues = BACKGROUND.lock().unwrap();
+        queues.entry(which_database).or_insert_with(|| _spawn_background(which_database)).clone()
+    };
+    let write = match sender.try_send((sql, args)) {
+        Ok(_) => return,
+        Err(mpsc::error::TrySendError::Full((sql, _))) => {
+            tracing::warn!("spawn_exec queue of '{}' is full, dropped: {}", which_database, crate::sql::truncate_for_log(&crate::sql::sanitize(&sql)));
+            return;
+        }
+        Err(mpsc::error::TrySendError::Closed(write)) => write,
+    };
+    // the task is gone with its runtime, start one on the current runtime
+    let sender = {
+        let mut queues = BACKGROUND.lock().unwrap();
+        let queue = queues.entry(which_database).or_insert_with(|| _spawn_background(which_database));
+        if queue.same_channel(&sender) {
+            *queue = _spawn_background(which_database);
+        }
+        queue.clone()
+    };
+    if let Err(err) = sender.try_send(write) {
+        let (sql, _) = err.into_inner();
+        tracing::error!("spawn_exec worker of '{}' is gone, dropped: {}", which_database, crate::sql::truncate_for_log(&crate::sql::sanitize(&sql)));
+    }
+}
+
+fn _spawn_background(which_database: &'static str) -> mpsc::Sender<BackgroundWrite> {
+    let size = match _opt_val::<usize>(&format!("{}.BACKGROUND_QUEUE", _which(which_database))) {
+        Ok(Some(size)) if size > 0 => size,
+        Ok(Some(_)) | Err(_) => {
+            tracing::error!("invalid {}.BACKGROUND_QUEUE, using {}", _which(which_database), DEFAULT_BACKGROUND_QUEUE);
+            DEFAULT_BACKGROUND_QUEUE
+        }
+        Ok(None) => DEFAULT_BACKGROUND_QUEUE,
+    };
+    let (sender, mut receiver) = mpsc::channel::<BackgroundWrite>(size);
+    tokio::spawn(async move {
+        while let Some((sql, args)) = receiver.recv().await {
+            let mut conn = match _background_conn(which_database).await {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let res = sqlx::query_with(&sql, args).execute(&mut *conn).await;
+            if let Err(err) = res {
+                error::database(KIND, "spawn_exec", err);
+            }
+        }
+    });
+    sender
+}
+
+// a connection for the next background write, retried with backoff for a short outage
+async fn _background_conn(which_database: &'static str) -> Option<PoolConnection<MySql>> {
+    let deadline = Instant::now() + BACKGROUND_RETRY;
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match _get_conn(which_database).await {
+            Ok(conn) => return Some(conn),
+            Err(err) if Instant::now() + backoff > deadline => {
+                tracing::error!("spawn_exec on '{}' dropped a write: {}", which_database, err);
+                return None;
+            }
+            Err(err) => {
+                tracing::warn!("spawn_exec on '{}' retrying in {:?}: {}", which_database, backoff, err);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(BACKGROUND_MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Connects the default database. Repeated or concurrent calls connect only once,
+/// use `reconnect` to rebuild an existing pool.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
 pub async fn setup() -> Result<()> {
     _setup(DEFAULT).await
 }
 
-#[instrument]
+#[cfg_attr(feature = "tracing-instrument", instrument)]
 pub async fn setup_database(which_database: &'static str) -> Result<()> {
     _setup(which_database).await
 }
 
-#[instrument]
+/// `setup_database` with a hook to tweak the connect options built from env before connecting,
+/// for sqlx options this crate doesn't read from env (`pipes_as_concat`, `timezone`, ...).
+///
+/// The hook is kept and applied again by `reconnect`. If the database is already set up it only
+/// takes effect on the next `reconnect`.
+#[cfg_attr(feature = "tracing-instrument", instrument(skip(configure)))]
+pub async fn setup_with_options<F>(which_database: &'static str, configure: F) -> Result<()>
+where
+    F: Fn(MySqlConnectOptions) -> MySqlConnectOptions + Send + Sync + 'static,
+{
+    CONFIGURES.lock().await.insert(which_database, Arc::new(configure));
+    _setup(which_database).await
+}
+
+/// Rebuilds the pool of `which_database` from the current env, e.g. after credentials rotated.
+///
+/// The new pool is swapped into the map before the old one is closed, so new `get_conn`
+/// calls immediately use fresh connections. Connections already checked out of the old
+/// pool keep working until they are dropped; `reconnect` returns once they have all drained,
+/// and during that window both pools hold server connections.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn reconnect(which_database: &'static str) -> Result<()> {
+    _reconnect(which_database).await
+}
+
+/// Closes every pool, waiting for checked out connections to be returned.
+///
+/// Tasks waiting in `get_conn` meanwhile, and any `get_conn` afterwards, fail with
+/// "pool is shutting down". `setup` can connect the databases again. Same as `close_all_ordered`
+/// with no order.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn close_all() {
+    close_all_ordered(&[]).await
+}
+
+/// `close_all` closing the databases of `order` first, one after another in that order, then
+/// the other ones by name. Each database closes its read replicas before its primary, so read
+/// traffic drains first. Every pool logs how many connections it closed and how long that took,
+/// a pool waits for its checked out connections, so a slow close points at a long running query
+/// or a leaked connection.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn close_all_ordered(order: &[&'static str]) {
+    let mut pools: Vec<(&'static str, Pool<MySql>)> = {
+        let map = POOLS.lock().await;
+        map.iter().map(|(k, v)| (*k, v.clone())).collect()
+    };
+    for which_database in order.iter().filter(|a| !pools.iter().any(|(b, _)| b == *a)) {
+        tracing::warn!("close_all_ordered: database '{}' is not set up", which_database);
+    }
+    pools.sort_by_key(|(which_database, _)| (order.iter().position(|a| a == which_database).unwrap_or(order.len()), *which_database));
+    SETUPS.lock().await.clear();
+    let replicas = REPLICAS.lock().await.clone();
+    for (which_database, pool) in pools {
+        if let Some(replicas) = replicas.get(which_database) {
+            for replica in replicas.list.iter() {
+                _close_pool(&replica.pool, &format!("read replica closed: {} of {}", replica.url, which_database)).await;
+            }
+        }
+        _close_pool(&pool, &format!("pool closed: {}", which_database)).await;
+    }
+}
+
+async fn _close_pool(pool: &Pool<MySql>, label: &str) {
+    let size = pool.size();
+    let in_use = size.saturating_sub(pool.num_idle() as u32);
+    let start = Instant::now();
+    pool.close().await;
+    tracing::info!(drained = size, in_use, elapsed_ms = start.elapsed().as_millis() as u64, "{}, {} connections drained, {} were in use", label, size, in_use);
+}
+
+/// Cheap health check, sends a protocol level ping on a pooled connection.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn ping(which_database: &'static str) -> Result<()> {
+    let mut conn = _get_conn(which_database).await?;
+    let res = conn.ping().await;
+    _circuit_record(which_database, res.is_ok());
+    match res {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            return Err(error::database(KIND, "ping", err));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CircuitState {
+    /// acquires go through, also when no circuit breaker is configured
+    Closed,
+    /// acquires fail fast until the cooldown is over
+    Open,
+    /// the cooldown is over, the next acquire is let through as a probe
+    HalfOpen,
+}
+
+/// The circuit breaker state of `which_database`, for health endpoints.
+///
+/// With `MYSQL.CIRCUIT_FAILURES` set, that many consecutive acquire or `ping` failures within
+/// `MYSQL.CIRCUIT_WINDOW_SECS` (default 10) open the circuit: `get_conn` then fails at once,
+/// see `error::is_circuit_open`, instead of waiting on an acquire that is bound to fail. After
+/// `MYSQL.CIRCUIT_COOLDOWN_SECS` (default 30) a single acquire is let through as a probe, its
+/// success closes the circuit and its failure opens it for another cooldown.
+pub fn circuit_state(which_database: &'static str) -> CircuitState {
+    let circuits = CIRCUITS.lock().unwrap();
+    let circuit = match circuits.get(which_database) {
+        Some(circuit) => circuit,
+        None => return CircuitState::Closed,
+    };
+    match circuit.opened_at {
+        None => CircuitState::Closed,
+        Some(at) if at.elapsed() < circuit.cooldown => CircuitState::Open,
+        Some(_) => CircuitState::HalfOpen,
+    }
+}
+
+/// Tasks currently waiting in `get_conn` for a connection of `which_database`.
+///
+/// Above zero only while every connection is in use, a depth that stays up under normal load
+/// means the pool is the bottleneck and `MYSQL.MAX_CONNECTS` is worth raising, as long as the
+/// server has room for the connections.
+pub fn acquire_wait_depth(which_database: &'static str) -> usize {
+    match ACQUIRE_WAITS.lock().unwrap().get(which_database) {
+        Some(waits) => waits.load(Ordering::Relaxed),
+        None => 0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// open connections, in use or idle
+    pub size: u32,
+    pub idle: usize,
+    /// see `acquire_wait_depth`
+    pub acquire_wait_depth: usize,
+    /// moving average of how long `get_conn` waited for a connection, weighting the latest
+    /// acquire by 10%, zero before the first one
+    pub acquire_wait_avg: Duration,
+}
+
+/// A snapshot of the pool of `which_database`, for metrics and health endpoints.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn pool_stats(which_database: &'static str) -> Result<PoolStats> {
+    let pool = _pool(which_database).await?;
+    Ok(PoolStats {
+        size: pool.size(),
+        idle: pool.num_idle(),
+        acquire_wait_depth: acquire_wait_depth(which_database),
+        acquire_wait_avg: match ACQUIRE_AVGS.lock().unwrap().get(which_database) {
+            Some(avg) => Duration::from_secs_f64(avg.avg_ms / 1000.0),
+            None => Duration::ZERO,
+        },
+    })
+}
+
+/// The `MYSQL.MAX_CONNECTS` the load of the last 15 minutes asks for: the peak of connections
+/// in use plus tasks waiting for one, sampled at every acquire, with 25% headroom, at least 1.
+///
+/// Waiting tasks are counted as they would have held a connection, so a suggestion above the
+/// configured max means the pool was exhausted, it is then logged at `info` every 5 minutes.
+/// Check `max_connections` of the server before raising it, every instance opens its own pool.
+pub fn suggested_max_connections(which_database: &'static str) -> u32 {
+    let peak = match PEAKS.lock().unwrap().get(which_database) {
+        Some(window) => window.peak(),
+        None => 0,
+    };
+    _with_headroom(peak)
+}
+
+/// Round trip latency of `which_database`, timed from acquiring a connection to the `SELECT 1` response.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn ping_latency(which_database: &'static str) -> Result<Duration> {
+    let start = Instant::now();
+    let mut conn = _get_conn(which_database).await?;
+    let res = sqlx::query("SELECT 1").execute(&mut *conn).await;
+    match res {
+        Ok(_) => Ok(start.elapsed()),
+        Err(err) => {
+            return Err(error::database(KIND, "ping_latency", err));
+        }
+    }
+}
+
+/// The `max_allowed_packet` of `which_database`, read from `MYSQL.MAX_ALLOWED_PACKET`
+/// when configured so no round trip is needed, otherwise queried once from the server.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn max_allowed_packet(which_database: &'static str) -> Result<u64> {
+    if let Some(limit) = PACKET_LIMITS.lock().await.get(which_database) {
+        return Ok(*limit);
+    }
+    let mut conn = _get_conn(which_database).await?;
+    let limit = mysql::max_allowed_packet(&mut conn).await?;
+    PACKET_LIMITS.lock().await.insert(which_database, limit);
+    Ok(limit)
+}
+
+/// The server version of `which_database`, queried once and cached until the next `reconnect`.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn server_version(which_database: &'static str) -> Result<ServerVersion> {
+    if let Some(version) = SERVER_VERSIONS.lock().await.get(which_database) {
+        return Ok(*version);
+    }
+    let mut conn = _get_conn(which_database).await?;
+    let version = mysql::server_version(&mut conn).await?;
+    SERVER_VERSIONS.lock().await.insert(which_database, version);
+    Ok(version)
+}
+
+/// The server clock of `which_database`, `SELECT UTC_TIMESTAMP(6)`, with the `chrono` feature.
+#[cfg(feature = "chrono")]
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn db_now(which_database: &'static str) -> Result<chrono::DateTime<chrono::Utc>> {
+    let mut conn = _get_conn(which_database).await?;
+    let res = sqlx::query_scalar::<_, chrono::NaiveDateTime>("SELECT UTC_TIMESTAMP(6)").fetch_one(&mut *conn).await;
+    match res {
+        Ok(now) => Ok(now.and_utc()),
+        Err(err) => {
+            return Err(error::database(KIND, "db_now", err));
+        }
+    }
+}
+
+// skew above which clock_skew warns
+#[cfg(feature = "chrono")]
+const MAX_CLOCK_SKEW_MS: i64 = 1000;
+
+/// How far the server clock of `which_database` is ahead of the local one, negative when it
+/// is behind, with the `chrono` feature. Warns when it is more than one second either way,
+/// drift like that reorders timestamps written by the app and by `NOW()`.
+///
+/// The server time is compared with the local time halfway through the round trip, so the
+/// result is accurate to about half the latency.
+#[cfg(feature = "chrono")]
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn clock_skew(which_database: &'static str) -> Result<chrono::Duration> {
+    let mut conn = _get_conn(which_database).await?;
+    let start = chrono::Utc::now();
+    let res = sqlx::query_scalar::<_, chrono::NaiveDateTime>("SELECT UTC_TIMESTAMP(6)").fetch_one(&mut *conn).await;
+    let end = chrono::Utc::now();
+    let now = match res {
+        Ok(now) => now.and_utc(),
+        Err(err) => {
+            return Err(error::database(KIND, "clock_skew", err));
+        }
+    };
+    let local = start + (end - start) / 2;
+    let skew = now - local;
+    if skew.num_milliseconds().abs() > MAX_CLOCK_SKEW_MS {
+        tracing::warn!("database '{}' clock is off by {} ms from the local clock", which_database, skew.num_milliseconds());
+    }
+    Ok(skew)
+}
+
+/// A row of `information_schema.processlist`, a session of the server.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProcessInfo {
+    /// the id for `kill_query` / `kill_connection`, `CONNECTION_ID()` of the session
+    pub id: u64,
+    pub user: String,
+    pub host: String,
+    pub db: Option<String>,
+    /// e.g. `Query` or `Sleep`
+    pub command: String,
+    /// seconds in the current state
+    pub time: i64,
+    pub state: Option<String>,
+    /// the running statement, `None` when idle
+    pub info: Option<String>,
+}
+
+/// The sessions of the server of `which_database`, longest running first, for admin tools.
+///
+/// Without the `PROCESS` privilege the server only lists the sessions of the own user.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn list_processes(which_database: &'static str) -> Result<Vec<ProcessInfo>> {
+    let mut conn = _get_conn(which_database).await?;
+    let res = sqlx::query_as::<_, (u64, String, String, Option<String>, String, i64, Option<String>, Option<String>)>(
+        "SELECT CAST(id AS UNSIGNED), user, host, db, command, CAST(time AS SIGNED), state, info FROM information_schema.processlist ORDER BY time DESC"
+    ).fetch_all(&mut *conn).await;
+    match res {
+        Ok(rows) => Ok(rows.into_iter().map(|(id, user, host, db, command, time, state, info)| ProcessInfo { id, user, host, db, command, time, state, info }).collect()),
+        Err(err) => {
+            return Err(error::database(KIND, "list_processes", err));
+        }
+    }
+}
+
+/// Stops the statement session `id` is running, the session stays connected.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn kill_query(which_database: &'static str, id: u64) -> Result<()> {
+    _kill(which_database, "kill_query", &format!("KILL QUERY {}", id), id).await
+}
+
+/// Disconnects session `id`, rolling back its open transaction.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn kill_connection(which_database: &'static str, id: u64) -> Result<()> {
+    _kill(which_database, "kill_connection", &format!("KILL CONNECTION {}", id), id).await
+}
+
+async fn _kill(which_database: &'static str, context: &str, sql: &str, id: u64) -> Result<()> {
+    let mut conn = _get_conn(which_database).await?;
+    let res = (&mut *conn).execute(sql).await;
+    match res {
+        Ok(_) => {
+            tracing::warn!("{} {} done", context, id);
+            Ok(())
+        }
+        Err(err) if error::mysql_errno(&err) == Some(1095) => {
+            let err = format!("{} failed: not allowed to kill session {} of another user, needs the CONNECTION_ADMIN or SUPER privilege", context, id);
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+        Err(err) if error::mysql_errno(&err) == Some(1094) => {
+            let err = format!("{} failed: no session {}, it may have ended already", context, id);
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+        Err(err) => {
+            return Err(error::database(KIND, context, err));
+        }
+    }
+}
+
+/// The connection charset and collation of `which_database` as detected at setup, or `None` if
+/// it is not set up or the detection failed.
+pub async fn charset(which_database: &'static str) -> Option<Charset> {
+    CHARSETS.lock().await.get(which_database).cloned()
+}
+
+/// `mysql::upsert_sql` for the server version of `which_database`.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn upsert_sql(which_database: &'static str, table: &str, columns: &[&str], update_columns: &[&str]) -> Result<String> {
+    let version = server_version(which_database).await?;
+    mysql::upsert_sql(table, columns, update_columns, &version)
+}
+
+/// Checks a `BLOB` / `LONGTEXT` argument against `max_allowed_packet` before binding it,
+/// see `mysql::check_packet_len`.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn check_packet_len(which_database: &'static str, len: usize) -> Result<()> {
+    let limit = max_allowed_packet(which_database).await?;
+    mysql::check_packet_len(len, limit)
+}
+
+/// Begins a transaction on `conn`, end it with `commit` or `rollback`, see `TransactionGuard`
+/// for one that is dropped instead.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
 pub async fn get_trans<'q>(
     conn: &'q mut PoolConnection<MySql>,
-) -> Result<Transaction<'q, sqlx::MySql>> {
+) -> Result<TransactionGuard<'q>> {
     _get_trans(&mut *conn).await
 }
 
-#[instrument]
-pub async fn commit<'q>(trans: Transaction<'q, MySql>) -> Result<()> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    /// the InnoDB default
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// `get_trans` at isolation level `level`.
+///
+/// The level is set with `SET TRANSACTION`, which only applies to the next transaction of the
+/// session, never with `SET SESSION`, so the next user of the pooled connection starts at the
+/// server default again. The one gap is a `BEGIN` that fails after the level was set, or a
+/// future dropped in between: the level stays pending for the next transaction on the
+/// connection, so on an error `close()` the connection instead of dropping it back to the pool.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn get_trans_with_isolation(conn: &mut PoolConnection<MySql>, level: IsolationLevel) -> Result<TransactionGuard<'_>> {
+    let res = (&mut **conn).execute(format!("SET TRANSACTION ISOLATION LEVEL {}", level.as_sql()).as_str()).await;
+    if let Err(err) = res {
+        return Err(error::database(KIND, "get_trans_with_isolation", err));
+    }
+    _get_trans(conn).await
+}
+
+/// Why `try_begin` failed, and so what to do with the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BeginFailure {
+    /// the connection broke (I/O, TLS or protocol error), drop it, the pool pings returned
+    /// connections and closes this one rather than handing it out again
+    ConnectionLost,
+    /// the server answered `BEGIN` with an error, the connection is fine and can be reused
+    Refused,
+}
+
+/// `get_trans` that says what a failed `BEGIN` means for `conn`.
+///
+/// `conn` is only borrowed and stays with the caller either way. A failed `BEGIN` leaves no
+/// transaction open on it, so dropping it back to the pool is always safe, after
+/// `BeginFailure::Refused` it can also be used right away.
+#[cfg_attr(feature = "tracing-instrument", instrument)]
+pub async fn try_begin<'q>(conn: &'q mut PoolConnection<MySql>) -> std::result::Result<TransactionGuard<'q>, (BeginFailure, DbError)> {
+    match _begin(conn).await {
+        Ok(tx) => Ok(tx),
+        Err(err) => {
+            let failure = match err {
+                sqlx::Error::Database(_) => BeginFailure::Refused,
+                _ => BeginFailure::ConnectionLost,
+            };
+            return Err((failure, error::database(KIND, "try_begin", err)));
+        }
+    }
+}
+
+/// A `tx` span with the id of `trans`, instrument the work inside the transaction with it
+/// so its statements show up under the transaction in traces.
+pub fn tx_span(trans: &TransactionGuard<'_>) -> Span {
+    tracing::info_span!("tx", tx_id = trans.id())
+}
+
+#[cfg_attr(feature = "tracing-instrument", instrument(skip(trans), fields(tx_id = trans.id())))]
+pub async fn commit<'q>(trans: TransactionGuard<'q>) -> Result<()> {
+    let (trans, tx_id, hooks) = trans.end();
     let res = trans.commit().await;
     match res {
-        Ok(_) => Ok(()),
+        Ok(_) => {
+            tracing::debug!(tx_id, "tx.commit");
+            _run_commit_hooks(hooks);
+            Ok(())
+        }
         Err(err) => {
-            tracing::error!("{}", err);
-            return Err(Error::Database(format!("commit failed:{:?}", err)));
+            return Err(error::database(KIND, "commit", err));
+        }
+    }
+}
+
+/// Commits `trans`, handing it back on failures that leave the transaction open so the
+/// caller can retry the commit.
+///
+/// Only a lock wait timeout (1205) is retryable: InnoDB rolls back the failed statement,
+/// not the transaction. Everything else comes back with `None` and the transaction is
+/// rolled back: a deadlock (1213) or a group replication conflict (3101) has already
+/// rolled it back on the server, so retry the whole transaction instead, and after an I/O
+/// error the outcome of the commit is unknown.
+///
+/// InnoDB takes its row locks while the statements run, so a `COMMIT` itself practically
+/// never waits for one and the retry case is rare, most failures come back as `None`. Retry
+/// deadlocks with `tx::run_in_transaction_retry`.
+///
+/// This sends a plain `COMMIT`, which ends the whole transaction. That is right for every
+/// `TransactionGuard`, they begin on a pooled connection and are never nested in another
+/// transaction. The hooks of `on_commit` run once the transaction is closed.
+#[cfg_attr(feature = "tracing-instrument", instrument(skip(trans), fields(tx_id = trans.id())))]
+pub async fn try_commit<'q>(mut trans: TransactionGuard<'q>) -> std::result::Result<(), (Option<TransactionGuard<'q>>, DbError)> {
+    let res = (&mut **trans).execute("COMMIT").await;
+    match res {
+        Ok(_) => {
+            let (trans, tx_id, hooks) = trans.end();
+            // the server has committed, the COMMIT sent by sqlx to close the handle is a no-op
+            match trans.commit().await {
+                Ok(_) => {
+                    tracing::debug!(tx_id, "tx.commit");
+                    _run_commit_hooks(hooks);
+                    Ok(())
+                }
+                Err(err) => Err((None, error::database(KIND, "try_commit", err))),
+            }
+        }
+        Err(err) => {
+            let retryable = error::mysql_errno(&err) == Some(1205);
+            let err = error::database(KIND, "try_commit", err);
+            if retryable {
+                return Err((Some(trans), err));
+            } else {
+                let (_, tx_id, _) = trans.end();
+                tracing::debug!(tx_id, "tx.rollback");
+                return Err((None, err));
+            }
         }
     }
 }
 
-#[instrument]
-pub async fn rollback<'q>(trans: Transaction<'q, MySql>) -> Result<()> {
+#[cfg_attr(feature = "tracing-instrument", instrument(skip(trans), fields(tx_id = trans.id())))]
+pub async fn rollback<'q>(trans: TransactionGuard<'q>) -> Result<()> {
+    let (trans, tx_id, _) = trans.end();
     let res = trans.rollback().await;
     match res {
-        Ok(_) => Ok(()),
+        Ok(_) => {
+            tracing::debug!(tx_id, "tx.rollback");
+            Ok(())
+        }
         Err(err) => {
-            tracing::error!("{}", err);
-            return Err(Error::Database(format!("rollback failed:{:?}", err)));
+            return Err(error::database(KIND, "rollback", err));
         }
     }
 }
 
 async fn _get_trans<'q>(
     conn: &'q mut PoolConnection<MySql>,
-) -> Result<Transaction<'q, sqlx::MySql>> {
-    let res = conn.begin().await;
-    match res {
+) -> Result<TransactionGuard<'q>> {
+    match _begin(conn).await {
         Ok(tx) => Ok(tx),
         Err(err) => {
-            tracing::error!("{}", err);
-            return Err(Error::Database(format!("_get_trans failed:{:?}", err)));
+            return Err(error::database(KIND, "_get_trans", err));
         }
     }
 }
 
+async fn _begin<'q>(conn: &'q mut PoolConnection<MySql>) -> std::result::Result<TransactionGuard<'q>, sqlx::Error> {
+    let tx = TransactionGuard::new(conn.begin().await?);
+    tracing::debug!(tx_id = tx.id(), "tx.begin");
+    Ok(tx)
+}
+
+async fn _get_read_conn(which_database: &'static str) -> Result<PoolConnection<MySql>> {
+    let replicas = REPLICAS.lock().await.get(which_database).cloned();
+    let replicas = match replicas {
+        Some(replicas) if !replicas.list.is_empty() => replicas,
+        _ => return _get_conn(which_database).await,
+    };
+    let mut tried = vec![false; replicas.list.len()];
+    while let Some(i) = _pick_replica(&replicas, &tried) {
+        let replica = &replicas.list[i];
+        match replica.pool.acquire().await {
+            Ok(conn) => return Ok(conn),
+            Err(err) => {
+                tracing::warn!("read replica {} acquire failed: {}", replica.url, error::summary(&err));
+                *replica.failed_at.lock().unwrap() = Some(Instant::now());
+                tried[i] = true;
+            }
+        }
+    }
+    tracing::warn!("no read replica available for {}, using primary", which_database);
+    _get_conn(which_database).await
+}
+
+// weighted random pick among replicas not tried yet and not cooling down
+fn _pick_replica(replicas: &Replicas, tried: &[bool]) -> Option<usize> {
+    let now = Instant::now();
+    let available: Vec<usize> = (0..replicas.list.len()).filter(|i| {
+        let replica = &replicas.list[*i];
+        let cooling = match *replica.failed_at.lock().unwrap() {
+            Some(at) => now.duration_since(at) < replicas.cooldown,
+            None => false,
+        };
+        !tried[*i] && replica.weight > 0 && !cooling
+    }).collect();
+    let total: u64 = available.iter().map(|i| replicas.list[*i].weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut r = next_rand() % total;
+    for i in available {
+        let weight = replicas.list[i].weight as u64;
+        if r < weight {
+            return Some(i);
+        }
+        r -= weight;
+    }
+    None
+}
+
+// splitmix64, good enough to spread load and reproducible after seed_read_replicas
+pub(crate) fn next_rand() -> u64 {
+    let mut z = RNG.fetch_add(0x9E3779B97F4A7C15, Ordering::Relaxed).wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Runs `hook` once `trans` commits, e.g. to publish the events of a transactional outbox, and
+/// drops it if the transaction rolls back or is dropped.
+///
+/// Hooks run in the order they were registered, right after the `COMMIT` succeeded and before
+/// `commit` returns, on the task calling it. A transaction rerun by `tx::run_in_transaction_retry`
+/// starts without the hooks of the failed attempt. The data is committed whatever a hook
+/// does, so a hook handles its own failures, and spawns a task for async work:
+///
+/// ```ignore
+/// let event = OrderPlaced { id };
+/// pool::on_commit(tx, move || { tokio::spawn(publish(event)); });
+/// ```
+///
+/// The hooks live in `trans`, so a transaction dropped without commit takes them along.
+pub fn on_commit<F: FnOnce() + Send + 'static>(trans: &TransactionGuard<'_>, hook: F) {
+    trans.push_commit_hook(Box::new(hook));
+}
+
+fn _run_commit_hooks(hooks: Vec<crate::tx::CommitHook>) {
+    for hook in hooks {
+        hook();
+    }
+}
+
+// debug builds: counts a transaction of the task that began it in TX_TASKS while its guard lives
+pub(crate) struct TaskTx(#[cfg(debug_assertions)] Option<tokio::task::Id>);
+
+pub(crate) fn task_tx() -> TaskTx {
+    #[cfg(debug_assertions)]
+    {
+        let task = tokio::task::try_id();
+        if let Some(task) = task {
+            *TX_TASKS.lock().unwrap().entry(task).or_default() += 1;
+        }
+        TaskTx(task)
+    }
+    #[cfg(not(debug_assertions))]
+    TaskTx()
+}
+
+impl Drop for TaskTx {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        if let Some(task) = self.0 {
+            let mut tasks = TX_TASKS.lock().unwrap();
+            if let Some(count) = tasks.get_mut(&task) {
+                *count -= 1;
+                if *count == 0 {
+                    tasks.remove(&task);
+                }
+            }
+        }
+    }
+}
+
+// debug builds: an acquire on an exhausted pool from a task holding a transaction waits for a
+// connection that may only come back once that transaction ends, a classic self-deadlock.
+// Only transactions of this task count, but not which pool they are on.
+#[cfg(debug_assertions)]
+fn _check_self_deadlock(which_database: &'static str, pool: &Pool<MySql>) {
+    let task = match tokio::task::try_id() {
+        Some(task) => task,
+        None => return,
+    };
+    let holds_tx = TX_TASKS.lock().unwrap().contains_key(&task);
+    if holds_tx && pool.num_idle() == 0 && pool.size() >= pool.options().get_max_connections() {
+        tracing::error!("acquire on exhausted pool {} while this task holds an open transaction, it may wait for its own connection", which_database);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SelfDeadlockMode {
+    Error,
+    Warn,
+    Off,
+}
+
+// counts a connection of the tx helpers in HELD_TXS while alive
+pub(crate) struct TxHold(Option<(tokio::task::Id, &'static str)>);
+
+pub(crate) fn hold_tx(which_database: &'static str) -> TxHold {
+    let task = match tokio::task::try_id() {
+        Some(task) => task,
+        None => return TxHold(None),
+    };
+    *HELD_TXS.lock().unwrap().entry((task, which_database)).or_default() += 1;
+    TxHold(Some((task, which_database)))
+}
+
+impl Drop for TxHold {
+    fn drop(&mut self) {
+        if let Some(key) = self.0 {
+            let mut held = HELD_TXS.lock().unwrap();
+            if let Some(count) = held.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    held.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+// an acquire from inside run_in_transaction_retry / batch_commit_insert on their own exhausted
+// pool only gets a connection if another task releases one, and hangs until the acquire timeout
+// when none does. Fails at once unless MYSQL.SELF_DEADLOCK says otherwise, returns whether
+// the task holds a transaction on the pool
+fn _check_held_tx(which_database: &'static str, pool: &Pool<MySql>) -> Result<bool> {
+    let task = match tokio::task::try_id() {
+        Some(task) => task,
+        None => return Ok(false),
+    };
+    if !HELD_TXS.lock().unwrap().contains_key(&(task, which_database)) {
+        return Ok(false);
+    }
+    if pool.num_idle() > 0 || pool.size() < pool.options().get_max_connections() {
+        return Ok(true);
+    }
+    let mode = SELF_DEADLOCK_MODES.lock().unwrap().get(which_database).copied().unwrap_or(SelfDeadlockMode::Error);
+    let msg = format!("{} acquire refused, potential self-deadlock: the pool is exhausted and this task holds one of its connections in a transaction", which_database);
+    match mode {
+        SelfDeadlockMode::Error => {
+            tracing::error!("{}", msg);
+            return Err(DbError::new(ErrorKind::PotentialSelfDeadlock, msg));
+        }
+        SelfDeadlockMode::Warn => tracing::warn!("{}", msg),
+        SelfDeadlockMode::Off => {}
+    }
+    Ok(true)
+}
+
 async fn _get_conn(which_database: &'static str) -> Result<PoolConnection<MySql>> {
+    let pool = _pool(which_database).await?;
+    let held = _check_held_tx(which_database, &pool)?;
+    #[cfg(debug_assertions)]
+    if !held {
+        _check_self_deadlock(which_database, &pool);
+    }
+    _circuit_check(which_database)?;
+    // a pool timeout after a brief spike often clears at once, retry it as configured, each
+    // attempt waits out the acquire timeout again so the retries stay bounded
+    let retries = ACQUIRE_RETRIES.lock().unwrap().get(which_database).copied().unwrap_or(0);
+    let span = tracing::debug_span!("pool.acquire", db = which_database, acquire_wait_ms = tracing::field::Empty);
+    let start = Instant::now();
+    let connect = async {
+        let mut attempt = 0;
+        loop {
+            let res = {
+                let _waiting = AcquireWait::new(which_database);
+                pool.acquire().await
+            };
+            match res {
+                Err(sqlx::Error::PoolTimedOut) if attempt < retries => {
+                    attempt += 1;
+                    tracing::warn!("{} pool timed out, acquire retry {}/{}", which_database, attempt, retries);
+                    tokio::time::sleep(Duration::from_millis(10 * attempt as u64)).await;
+                }
+                res => break res,
+            }
+        }
+    }.instrument(span.clone()).await;
+    // waiting for a free connection looks like a slow query in traces otherwise
+    let waited = start.elapsed();
+    span.record("acquire_wait_ms", waited.as_millis() as u64);
+    let slow = SLOW_ACQUIRES.lock().unwrap().get(which_database).copied().unwrap_or(DEFAULT_SLOW_ACQUIRE);
+    if waited > slow {
+        tracing::warn!(parent: &span, acquire_wait_ms = waited.as_millis() as u64, "{} slow acquire, waited {:?} for a connection", which_database, waited);
+    }
+    _circuit_record(which_database, connect.is_ok());
+    _record_acquire_wait(which_database, waited);
+    if connect.is_ok() {
+        _record_peak(which_database, &pool);
+    }
+    match connect {
+        Ok(con) => Ok(con),
+        Err(err) => {
+            return Err(error::database(KIND, "_get_conn acquire", err));
+        }
+    }
+}
+
+async fn _pool(which_database: &'static str) -> Result<Pool<MySql>> {
     let map = POOLS.lock().await;
     let res = map.get(which_database);
     if let Some(pool) = res {
-        let connect = pool.acquire().await;
-        match connect {
-            Ok(con) => Ok(con),
+        Ok(pool.clone())
+    } else {
+        let err = format!("database '{}' is not set up; call setup()/setup_database() first", which_database);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+}
+
+/// sqlx keeps idle connections in a FIFO queue and has no switch for LIFO.
+/// FIFO rotates through every idle connection, so under steady load no connection
+/// stays idle long enough for `idle_timeout` to reap it and the pool stays at its
+/// peak size. LIFO would keep a small hot set and let the cold tail be reaped,
+/// which is why `lifo` is accepted but only warned about.
+fn _check_acquire_order(which: &str) -> Result<()> {
+    let key = format!("{}.ACQUIRE_ORDER", which);
+    let order = _opt_str(&key).unwrap_or("fifo".to_string());
+    match order.to_lowercase().as_str() {
+        "fifo" => Ok(()),
+        "lifo" => {
+            tracing::warn!("{} lifo is not supported by sqlx pool, using fifo", key);
+            Ok(())
+        }
+        _ => {
+            return Err(DbError::new(ErrorKind::Config, format!("{} must be fifo or lifo, got {}", key, order)));
+        }
+    }
+}
+
+fn _check_connects(which: &str, min_connects: u32, max_connects: u32) -> Result<()> {
+    if max_connects < 1 {
+        let err = format!("{}.MAX_CONNECTS must be at least 1, got {}", which, max_connects);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Config, err));
+    }
+    if min_connects > max_connects {
+        let err = format!("{}.MIN_CONNECTS ({}) must not exceed {}.MAX_CONNECTS ({})", which, min_connects, which, max_connects);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Config, err));
+    }
+    Ok(())
+}
+
+// a session timeout in seconds, 0 would make the server drop the connection at once
+fn _opt_timeout(which: &str, name: &str) -> Result<Option<u64>> {
+    let timeout = _opt_val::<u64>(&format!("{}.{}", which, name))?;
+    if timeout == Some(0) {
+        let err = format!("{}.{} must be a positive number of seconds", which, name);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Config, err));
+    }
+    Ok(timeout)
+}
+
+// counts a task in acquire_wait_depth while alive, also when the acquire is cancelled
+struct AcquireWait(Arc<AtomicUsize>);
+
+impl AcquireWait {
+    fn new(which_database: &'static str) -> Self {
+        let waits = ACQUIRE_WAITS.lock().unwrap().entry(which_database).or_default().clone();
+        waits.fetch_add(1, Ordering::Relaxed);
+        AcquireWait(waits)
+    }
+}
+
+impl Drop for AcquireWait {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// the peak of each of the last PEAK_WINDOW_MINUTES minutes, slot `minute % len`
+struct PeakWindow {
+    started: Instant,
+    slots: [(u64, u32); PEAK_WINDOW_MINUTES],
+    logged_at: Option<Instant>,
+}
+
+impl PeakWindow {
+    fn new() -> Self {
+        PeakWindow { started: Instant::now(), slots: [(0, 0); PEAK_WINDOW_MINUTES], logged_at: None }
+    }
+
+    fn _minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+
+    fn record(&mut self, demand: u32) {
+        let minute = self._minute();
+        let slot = &mut self.slots[minute as usize % PEAK_WINDOW_MINUTES];
+        if slot.0 != minute {
+            *slot = (minute, 0);
+        }
+        slot.1 = slot.1.max(demand);
+    }
+
+    fn peak(&self) -> u32 {
+        let minute = self._minute();
+        self.slots.iter()
+            .filter(|(at, _)| minute - at < PEAK_WINDOW_MINUTES as u64)
+            .map(|(_, peak)| *peak)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn _with_headroom(peak: u32) -> u32 {
+    (peak + peak.div_ceil(4)).max(1)
+}
+
+// in use counts the connection just acquired, waiting ones are those still queued behind it
+fn _record_peak(which_database: &'static str, pool: &Pool<MySql>) {
+    let in_use = pool.size().saturating_sub(pool.num_idle() as u32);
+    let demand = in_use + acquire_wait_depth(which_database) as u32;
+    let max_connects = pool.options().get_max_connections();
+    let suggested = {
+        let mut peaks = PEAKS.lock().unwrap();
+        let window = peaks.entry(which_database).or_insert_with(PeakWindow::new);
+        window.record(demand);
+        let suggested = _with_headroom(window.peak());
+        let due = window.logged_at.map(|a| a.elapsed() >= PEAK_LOG_INTERVAL).unwrap_or(true);
+        if suggested > max_connects && due {
+            window.logged_at = Some(Instant::now());
+            Some(suggested)
+        } else {
+            None
+        }
+    };
+    if let Some(suggested) = suggested {
+        tracing::info!("{} peak demand of the last {} minutes suggests MAX_CONNECTS {}, configured {}", which_database, PEAK_WINDOW_MINUTES, suggested, max_connects);
+    }
+}
+
+fn _opt_str(key: &str) -> Option<String> {
+    std::env::var(key).ok()
+}
+
+async fn _setup(which_database: &'static str) -> Result<()> {
+    let cell = {
+        let mut map = SETUPS.lock().await;
+        map.entry(which_database).or_insert_with(|| Arc::new(OnceCell::new())).clone()
+    };
+    cell.get_or_try_init(|| async {
+        _setup_circuit(which_database)?;
+        _setup_acquire(which_database)?;
+        let pool = _connect(which_database).await?;
+        _check_charset(which_database, &pool).await;
+        _check_database(which_database, &pool).await;
+        // nothing is stored before the replicas connected too, so a retry after a failed
+        // setup doesn't leave the pool of the earlier attempt behind
+        let replicas = match _connect_replicas(which_database).await {
+            Ok(replicas) => replicas,
             Err(err) => {
-                tracing::error!("{}", err);
-                return Err(Error::Database(format!("_get_conn acquire failed:{:?}", err)));
+                pool.close().await;
+                return Err(err);
             }
+        };
+        let old = POOLS.lock().await.insert(which_database, pool);
+        if let Some(old) = old {
+            old.close().await;
         }
-    } else {
-        return Err(Error::Database("_get_conn failed".to_string()));
+        _swap_replicas(which_database, replicas).await;
+        Ok::<(), DbError>(())
+    }).await?;
+    Ok(())
+}
+
+async fn _reconnect(which_database: &'static str) -> Result<()> {
+    _setup_circuit(which_database)?;
+    _setup_acquire(which_database)?;
+    let pool = _connect(which_database).await?;
+    _check_charset(which_database, &pool).await;
+    _check_database(which_database, &pool).await;
+    let replicas = match _connect_replicas(which_database).await {
+        Ok(replicas) => replicas,
+        Err(err) => {
+            pool.close().await;
+            return Err(err);
+        }
+    };
+    SERVER_VERSIONS.lock().await.remove(which_database);
+    let old = {
+        let mut map = POOLS.lock().await;
+        map.insert(which_database, pool)
+    };
+    if let Some(old) = old {
+        tracing::info!("closing replaced pool: {}", which_database);
+        old.close().await;
+        tracing::info!("replaced pool closed: {}", which_database);
     }
+    _swap_replicas(which_database, replicas).await;
+    Ok(())
 }
 
-async fn _setup(which_database: &'static str) -> Result<()> {
+// read replicas of `which_database` listed in `MYSQL.READ_URLS`, the ones connected already are
+// closed again when a later one fails
+async fn _connect_replicas(which_database: &'static str) -> Result<Arc<Replicas>> {
+    let which = _which(which_database);
+    let urls: Vec<String> = match _opt_str(&format!("{}.READ_URLS", which)) {
+        Some(v) => v.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect(),
+        None => Vec::new(),
+    };
+    let weights = match _opt_str(&format!("{}.READ_WEIGHTS", which)) {
+        Some(_) => env::vals::<u32>(&format!("{}.READ_WEIGHTS", which), ",")?,
+        None => vec![1; urls.len()],
+    };
+    if weights.len() != urls.len() || (!urls.is_empty() && weights.iter().all(|a| *a == 0)) {
+        let err = format!("{}.READ_WEIGHTS must have one weight per READ_URLS entry and not be all 0", which);
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Config, err));
+    }
+    let cooldown = _opt_val::<u64>(&format!("{}.READ_COOLDOWN_SECS", which))?.unwrap_or(30);
+    let mut list = Vec::with_capacity(urls.len());
+    for (url, weight) in urls.into_iter().zip(weights) {
+        match _connect_url(which_database, &url).await {
+            Ok(pool) => list.push(Replica { url, weight, pool, failed_at: std::sync::Mutex::new(None) }),
+            Err(err) => {
+                for replica in list.iter() {
+                    replica.pool.close().await;
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(Arc::new(Replicas { list, cooldown: Duration::from_secs(cooldown) }))
+}
+
+// replaces the read replicas of `which_database`, closing the earlier ones
+async fn _swap_replicas(which_database: &'static str, replicas: Arc<Replicas>) {
+    let old = REPLICAS.lock().await.insert(which_database, replicas);
+    if let Some(old) = old {
+        for replica in old.list.iter() {
+            replica.pool.close().await;
+        }
+    }
+}
+
+// warns when the connections don't use the schema of `MYSQL.DATABASE`, e.g. a proxy routing
+// them elsewhere
+async fn _check_database(which_database: &'static str, pool: &Pool<MySql>) {
+    let expected = match _opt_str(&format!("{}.DATABASE", _which(which_database))) {
+        Some(expected) => expected,
+        None => return,
+    };
+    let res = match pool.acquire().await {
+        Ok(mut conn) => mysql::current_database(&mut conn).await,
+        Err(err) => Err(error::database(KIND, "_check_database", err)),
+    };
+    match res {
+        Ok(current) if current != expected => {
+            tracing::warn!("{} connections use database {}, not the configured {}", which_database, current, expected);
+        }
+        Ok(_) => {}
+        Err(_) => {
+            tracing::warn!("{} current database could not be checked", which_database);
+        }
+    }
+}
+
+// warns when the server settled on another charset than `MYSQL.CHARSET` (sqlx asks for utf8mb4
+// by default), e.g. because the server doesn't know it or `init_connect` overrides it
+async fn _check_charset(which_database: &'static str, pool: &Pool<MySql>) {
+    let requested = _opt_str(&format!("{}.CHARSET", _which(which_database))).unwrap_or("utf8mb4".to_string());
+    let res = match pool.acquire().await {
+        Ok(mut conn) => mysql::connection_charset(&mut conn).await,
+        Err(err) => Err(error::database(KIND, "_check_charset", err)),
+    };
+    match res {
+        Ok(charset) => {
+            if !charset.character_set.eq_ignore_ascii_case(&requested) {
+                tracing::warn!("{} connection charset is {} ({}), not the requested {}", which_database, charset.character_set, charset.collation, requested);
+            }
+            CHARSETS.lock().await.insert(which_database, charset);
+        }
+        Err(_) => {
+            tracing::warn!("{} connection charset could not be detected", which_database);
+            CHARSETS.lock().await.remove(which_database);
+        }
+    }
+}
+
+struct Circuit {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    failures: u32,
+    first_failure_at: Option<Instant>,
+    opened_at: Option<Instant>,
+    // a probe that never reports back, e.g. cancelled, stops blocking the next one after a cooldown
+    probe_at: Option<Instant>,
+}
+
+fn _setup_acquire(which_database: &'static str) -> Result<()> {
+    // one rate for every database, the helpers don't know which database a connection is of
+    if let Some(rate) = _opt_val::<f64>("MYSQL.QUERY_LOG_SAMPLE_RATE")? {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(DbError::new(ErrorKind::Config, format!("MYSQL.QUERY_LOG_SAMPLE_RATE must be between 0 and 1, got {}", rate)));
+        }
+        crate::sql::set_query_log_sample_rate(rate);
+    }
+    match _opt_val::<u64>(&format!("{}.SLOW_ACQUIRE_MS", _which(which_database)))? {
+        Some(ms) => {
+            SLOW_ACQUIRES.lock().unwrap().insert(which_database, Duration::from_millis(ms));
+        }
+        None => {
+            SLOW_ACQUIRES.lock().unwrap().remove(which_database);
+        }
+    }
+    let which = _which(which_database);
+    match _opt_val::<u64>(&format!("{}.SLOW_ACQUIRE_AVG_MS", which))? {
+        Some(ms) if ms > 0 => {
+            let window = _opt_val::<u64>(&format!("{}.SLOW_ACQUIRE_AVG_SECS", which))?.unwrap_or(30);
+            let half_open = _opt_val::<bool>(&format!("{}.SLOW_ACQUIRE_HALF_OPEN", which))?.unwrap_or(false);
+            SLOW_ACQUIRE_ALARMS.lock().unwrap().insert(which_database, SlowAcquireAlarm {
+                threshold: Duration::from_millis(ms),
+                window: Duration::from_secs(window),
+                half_open,
+            });
+        }
+        _ => {
+            SLOW_ACQUIRE_ALARMS.lock().unwrap().remove(which_database);
+        }
+    }
+    let key = format!("{}.SELF_DEADLOCK", which);
+    let mode = match _opt_str(&key).map(|a| a.to_lowercase()).as_deref() {
+        None | Some("error") => SelfDeadlockMode::Error,
+        Some("warn") => SelfDeadlockMode::Warn,
+        Some("off") => SelfDeadlockMode::Off,
+        Some(other) => {
+            return Err(DbError::new(ErrorKind::Config, format!("{} must be error, warn or off, got {}", key, other)));
+        }
+    };
+    SELF_DEADLOCK_MODES.lock().unwrap().insert(which_database, mode);
+    match _opt_val::<u32>(&format!("{}.ACQUIRE_RETRIES", _which(which_database)))? {
+        Some(retries) if retries > 0 => {
+            ACQUIRE_RETRIES.lock().unwrap().insert(which_database, retries);
+        }
+        _ => {
+            ACQUIRE_RETRIES.lock().unwrap().remove(which_database);
+        }
+    }
+    Ok(())
+}
+
+struct AcquireAvg {
+    avg_ms: f64,
+    // since when the average is above the alarm threshold
+    slow_since: Option<Instant>,
+    alarmed: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SlowAcquireAlarm {
+    threshold: Duration,
+    window: Duration,
+    half_open: bool,
+}
+
+// slow acquires that still succeed are an early sign of trouble, an average above
+// MYSQL.SLOW_ACQUIRE_AVG_MS for the whole window warns once, and with SLOW_ACQUIRE_HALF_OPEN
+// half-opens the circuit so only a probe goes through until an acquire succeeds
+fn _record_acquire_wait(which_database: &'static str, waited: Duration) {
+    let alarm = SLOW_ACQUIRE_ALARMS.lock().unwrap().get(which_database).copied();
+    let waited_ms = waited.as_secs_f64() * 1000.0;
+    let (avg_ms, alarmed, recovered) = {
+        let mut avgs = ACQUIRE_AVGS.lock().unwrap();
+        let avg = avgs.entry(which_database).or_insert(AcquireAvg { avg_ms: waited_ms, slow_since: None, alarmed: false });
+        avg.avg_ms = ACQUIRE_AVG_ALPHA * waited_ms + (1.0 - ACQUIRE_AVG_ALPHA) * avg.avg_ms;
+        let alarm = match alarm {
+            Some(alarm) => alarm,
+            None => return,
+        };
+        if avg.avg_ms > alarm.threshold.as_secs_f64() * 1000.0 {
+            let since = *avg.slow_since.get_or_insert_with(Instant::now);
+            let alarmed = !avg.alarmed && since.elapsed() >= alarm.window;
+            avg.alarmed |= alarmed;
+            (avg.avg_ms, alarmed, false)
+        } else {
+            let recovered = avg.alarmed;
+            avg.slow_since = None;
+            avg.alarmed = false;
+            (avg.avg_ms, false, recovered)
+        }
+    };
+    if recovered {
+        tracing::info!("{} acquire wait average back to {:.1}ms", which_database, avg_ms);
+    }
+    if let (true, Some(alarm)) = (alarmed, alarm) {
+        tracing::warn!("{} acquire wait average {:.1}ms above {:?} for {:?}", which_database, avg_ms, alarm.threshold, alarm.window);
+        if alarm.half_open {
+            _circuit_half_open(which_database);
+        }
+    }
+}
+
+fn _setup_circuit(which_database: &'static str) -> Result<()> {
+    let which = _which(which_database);
+    let threshold = match _opt_val::<u32>(&format!("{}.CIRCUIT_FAILURES", which))? {
+        Some(threshold) if threshold > 0 => threshold,
+        _ => {
+            CIRCUITS.lock().unwrap().remove(which_database);
+            return Ok(());
+        }
+    };
+    let window = _opt_val::<u64>(&format!("{}.CIRCUIT_WINDOW_SECS", which))?.unwrap_or(10);
+    let cooldown = _opt_val::<u64>(&format!("{}.CIRCUIT_COOLDOWN_SECS", which))?.unwrap_or(30);
+    CIRCUITS.lock().unwrap().insert(which_database, Circuit {
+        threshold,
+        window: Duration::from_secs(window),
+        cooldown: Duration::from_secs(cooldown),
+        failures: 0,
+        first_failure_at: None,
+        opened_at: None,
+        probe_at: None,
+    });
+    Ok(())
+}
+
+fn _circuit_check(which_database: &'static str) -> Result<()> {
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = match circuits.get_mut(which_database) {
+        Some(circuit) => circuit,
+        None => return Ok(()),
+    };
+    let opened_at = match circuit.opened_at {
+        Some(at) => at,
+        None => return Ok(()),
+    };
+    let probing = circuit.probe_at.is_some_and(|at| at.elapsed() < circuit.cooldown);
+    if opened_at.elapsed() >= circuit.cooldown && !probing {
+        circuit.probe_at = Some(Instant::now());
+        return Ok(());
+    }
+    let err = format!("database '{}' circuit open after {} failures, failing fast", which_database, circuit.threshold);
+    tracing::warn!("{}", err);
+    return Err(DbError::new(ErrorKind::CircuitOpen, err));
+}
+
+// an open circuit whose cooldown is over, the next acquire is the probe
+fn _circuit_half_open(which_database: &'static str) {
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = match circuits.get_mut(which_database) {
+        Some(circuit) => circuit,
+        None => return,
+    };
+    if circuit.opened_at.is_none() {
+        let now = Instant::now();
+        circuit.opened_at = Some(now.checked_sub(circuit.cooldown).unwrap_or(now));
+        circuit.probe_at = None;
+        tracing::warn!("database '{}' circuit half-open after sustained slow acquires", which_database);
+    }
+}
+
+fn _circuit_record(which_database: &'static str, ok: bool) {
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = match circuits.get_mut(which_database) {
+        Some(circuit) => circuit,
+        None => return,
+    };
+    let now = Instant::now();
+    if ok {
+        if circuit.opened_at.is_some() {
+            tracing::info!("database '{}' circuit closed", which_database);
+        }
+        circuit.failures = 0;
+        circuit.first_failure_at = None;
+        circuit.opened_at = None;
+        circuit.probe_at = None;
+        return;
+    }
+    if circuit.opened_at.is_some() {
+        // the probe failed
+        circuit.opened_at = Some(now);
+        circuit.probe_at = None;
+        tracing::warn!("database '{}' circuit probe failed, open again", which_database);
+        return;
+    }
+    match circuit.first_failure_at {
+        Some(at) if now.duration_since(at) <= circuit.window => circuit.failures += 1,
+        _ => {
+            circuit.first_failure_at = Some(now);
+            circuit.failures = 1;
+        }
+    }
+    if circuit.failures >= circuit.threshold {
+        circuit.opened_at = Some(now);
+        tracing::warn!("database '{}' circuit open after {} failures", which_database, circuit.failures);
+    }
+}
+
+fn _opt_val<F: FromStr>(key: &str) -> Result<Option<F>> {
+    match _opt_str(key) {
+        Some(v) => Ok(Some(val::str_to_val::<F>(&v)?)),
+        None => Ok(None),
+    }
+}
+
+// session state applied to every new physical connection
+struct SessionInit {
+    app_name: Option<String>,
+    // `SET SESSION` assignments for the configured timeouts
+    timeouts: Vec<String>,
+}
+
+async fn _init_session(conn: &mut MySqlConnection, session: &SessionInit) -> std::result::Result<(), sqlx::Error> {
+    // what mysql::reset_session goes back to, after sqlx set up the session
+    (&mut *conn).execute("SET @_qx_sql_mode = @@SESSION.sql_mode, @_qx_time_zone = @@SESSION.time_zone").await?;
+    if let Some(app_name) = &session.app_name {
+        sqlx::query("SET @app_name = ?").bind(app_name).execute(&mut *conn).await?;
+    }
+    if !session.timeouts.is_empty() {
+        let sql = format!("SET SESSION {}", session.timeouts.join(", "));
+        (&mut *conn).execute(sql.as_str()).await?;
+    }
+    let warm_queries = WARM_QUERIES.lock().unwrap().clone();
+    for sql in warm_queries.iter() {
+        if let Err(err) = (&mut *conn).prepare(sql).await {
+            tracing::error!("warm query prepare failed: {}: {}", crate::sql::truncate_for_log(sql), error::summary(&err));
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn _which(which_database: &'static str) -> String {
     let mut which = "MYSQL".to_string();
     if which_database != DEFAULT {
         which = format!("MYSQL.{}", which_database);
     }
-    let url = env::str(&format!("{}.URL", which))?;
+    which
+}
+
+async fn _connect(which_database: &'static str) -> Result<Pool<MySql>> {
+    let url = env::str(&format!("{}.URL", _which(which_database)))?;
+    _connect_url(which_database, &url).await
+}
+
+// debug events for connections the pool closed by itself, for idle_timeout, max_lifetime or
+// a failed ping on release, sqlx has no hook for those so the size is sampled
+async fn _watch_pool(label: Arc<str>, pool: Pool<MySql>, opened: Arc<AtomicU64>) {
+    let mut size = pool.size() as u64;
+    let mut last_opened = opened.load(Ordering::Relaxed);
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        if pool.is_closed() {
+            tracing::debug!(pool = %label, "pool.closed");
+            return;
+        }
+        let now_size = pool.size() as u64;
+        let now_opened = opened.load(Ordering::Relaxed);
+        let closed = (size + now_opened - last_opened).saturating_sub(now_size);
+        if closed > 0 {
+            tracing::debug!(pool = %label, closed, size = now_size, idle = pool.num_idle(), "pool.connection.close");
+        }
+        size = now_size;
+        last_opened = now_opened;
+    }
+}
+
+// the pool of `url` with the settings of `which_database`, also used for its read replicas
+async fn _connect_url(which_database: &'static str, url: &str) -> Result<Pool<MySql>> {
+    let which = _which(which_database);
     let database: String = env::str(&format!("{}.DATABASE", which))?;
     let user_name = env::str(&format!("{}.USER_NAME", which))?;
     let password = env::str(&format!("{}.PASSWORD", which))?;
     let max_connects = env::val::<u32>(&format!("{}.MAX_CONNECTS", which))?;
+    let min_connects = _opt_val::<u32>(&format!("{}.MIN_CONNECTS", which))?.unwrap_or(0);
+    _check_connects(&which, min_connects, max_connects)?;
+    _check_acquire_order(&which)?;
+    let max_allowed_packet = _opt_val::<u64>(&format!("{}.MAX_ALLOWED_PACKET", which))?;
+    let test_before_acquire = _opt_val::<bool>(&format!("{}.TEST_BEFORE_ACQUIRE", which))?.unwrap_or(true);
+    let reset_on_release = _opt_val::<bool>(&format!("{}.RESET_ON_RELEASE", which))?.unwrap_or(false);
+    let charset = _opt_str(&format!("{}.CHARSET", which));
+    let mut timeouts = Vec::new();
+    for (name, variable) in [("NET_READ_TIMEOUT", "net_read_timeout"), ("NET_WRITE_TIMEOUT", "net_write_timeout"), ("WAIT_TIMEOUT", "wait_timeout")] {
+        if let Some(timeout) = _opt_timeout(&which, name)? {
+            timeouts.push(format!("{} = {}", variable, timeout));
+        }
+    }
+    let session = Arc::new(SessionInit {
+        app_name: _opt_str(&format!("{}.APP_NAME", which)),
+        timeouts,
+    });
+    let label: Arc<str> = Arc::from(format!("{}@{}", which_database, url));
+    let opened = Arc::new(AtomicU64::new(0));
     let full_url = format!("mysql://{}:{}@{}/{}", user_name, password, url, database);
 
-    tracing::info!("full_url: {}", full_url);
+    tracing::info!("full_url: mysql://{}:***@{}/{}", user_name, url, database);
 
     tracing::info!("connecting database: {}", database);
     let res = MySqlConnectOptions::from_str(&full_url);
     match res {
-        Ok(connection_options) => {
+        Ok(mut connection_options) => {
+            if let Some(charset) = &charset {
+                connection_options = connection_options.charset(charset);
+            }
+            let configure = CONFIGURES.lock().await.get(which_database).cloned();
+            if let Some(configure) = configure {
+                connection_options = configure(connection_options);
+            }
             let res = MySqlPoolOptions::new()
                 .max_connections(max_connects)
+                .min_connections(min_connects)
+                .test_before_acquire(test_before_acquire)
+                .after_release(move |conn, _| Box::pin(async move {
+                    if !reset_on_release {
+                        return Ok(true);
+                    }
+                    // a connection that couldn't be reset is closed rather than handed out dirty
+                    Ok(mysql::reset_session(conn).await.is_ok())
+                }))
+                .after_connect({
+                    let label = label.clone();
+                    let opened = opened.clone();
+                    move |conn, _| {
+                        let opened = opened.fetch_add(1, Ordering::Relaxed) + 1;
+                        tracing::debug!(pool = %label, opened, "pool.connection.open");
+                        let session = session.clone();
+                        Box::pin(async move { _init_session(conn, &session).await })
+                    }
+                })
                 .connect_with(connection_options)
                 .await;
             match res {
                 Ok(pool) => {
-                    let mut map = POOLS.lock().await;
-                    map.insert(which_database, pool);
+                    let mut limits = PACKET_LIMITS.lock().await;
+                    match max_allowed_packet {
+                        Some(limit) => limits.insert(which_database, limit),
+                        None => limits.remove(which_database),
+                    };
                     tracing::info!("database connected");
-                    Ok(())
+                    tokio::spawn(_watch_pool(label, pool.clone(), opened));
+                    Ok(pool)
                 }
                 Err(err) => {
-                    tracing::error!("{}", err);
-                    return Err(Error::Database(format!("_setup connect_with failed:{:?}", err)));
+                    return Err(error::database(KIND, "_setup connect_with", err));
                 }
             }
         }
         Err(err) => {
-            tracing::error!("{}", err);
-            return Err(Error::Database(format!("_setup from_str failed:{:?}", err)));
+            return Err(error::database(KIND, "_setup from_str", err));
         }
     }
 }
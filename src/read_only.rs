@@ -0,0 +1,98 @@
+use std::fmt;
+
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use sqlx::mysql::{MySqlQueryResult, MySqlRow, MySqlStatement, MySqlTypeInfo};
+use sqlx::pool::PoolConnection;
+use sqlx::{Describe, Either, Execute, Executor, MySql, MySqlConnection};
+
+use crate::mysql::Conn;
+use crate::sql;
+
+
+/// A pooled connection that only runs a single `SELECT`, `SHOW`, `EXPLAIN` or `WITH ... SELECT`
+/// statement, see `sql::is_read_only`, anything else fails with "write attempted on read-only
+/// connection" before it is sent. Get one with `pool::get_read_only_conn` and pass `&mut conn`
+/// to the `exec*` helpers.
+///
+/// A safety net on top of the grants of the database user, not a replacement: stored functions
+/// with side effects still pass the check.
+pub struct ReadOnlyConn(PoolConnection<MySql>);
+
+impl ReadOnlyConn {
+    pub fn new(conn: PoolConnection<MySql>) -> Self {
+        ReadOnlyConn(conn)
+    }
+
+    /// The unguarded connection.
+    pub fn into_inner(self) -> PoolConnection<MySql> {
+        self.0
+    }
+}
+
+impl fmt::Debug for ReadOnlyConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReadOnlyConn").field(&self.0).finish()
+    }
+}
+
+impl<'c> Conn<'c> for &'c mut ReadOnlyConn {
+    type Executor = ReadOnlyExecutor<'c>;
+
+    fn executor(self) -> Self::Executor {
+        ReadOnlyExecutor(&mut self.0)
+    }
+}
+
+/// The executor of a `ReadOnlyConn`, checking each statement before passing it on.
+#[derive(Debug)]
+pub struct ReadOnlyExecutor<'c>(&'c mut MySqlConnection);
+
+fn _denied() -> sqlx::Error {
+    sqlx::Error::Configuration(Box::new(crate::error::WriteOnReadOnly))
+}
+
+impl<'c> Executor<'c> for ReadOnlyExecutor<'c> {
+    type Database = MySql;
+
+    fn fetch_many<'e, 'q: 'e, E>(self, query: E) -> BoxStream<'e, Result<Either<MySqlQueryResult, MySqlRow>, sqlx::Error>>
+    where
+        'c: 'e,
+        E: 'q + Execute<'q, MySql>,
+    {
+        if !sql::is_read_only(query.sql()) {
+            return futures::stream::once(async { Err(_denied()) }).boxed();
+        }
+        self.0.fetch_many(query)
+    }
+
+    fn fetch_optional<'e, 'q: 'e, E>(self, query: E) -> BoxFuture<'e, Result<Option<MySqlRow>, sqlx::Error>>
+    where
+        'c: 'e,
+        E: 'q + Execute<'q, MySql>,
+    {
+        if !sql::is_read_only(query.sql()) {
+            return async { Err(_denied()) }.boxed();
+        }
+        self.0.fetch_optional(query)
+    }
+
+    fn prepare_with<'e, 'q: 'e>(self, sql: &'q str, parameters: &'e [MySqlTypeInfo]) -> BoxFuture<'e, Result<MySqlStatement<'q>, sqlx::Error>>
+    where
+        'c: 'e,
+    {
+        if !sql::is_read_only(sql) {
+            return async { Err(_denied()) }.boxed();
+        }
+        self.0.prepare_with(sql, parameters)
+    }
+
+    fn describe<'e, 'q: 'e>(self, sql: &'q str) -> BoxFuture<'e, Result<Describe<MySql>, sqlx::Error>>
+    where
+        'c: 'e,
+    {
+        if !sql::is_read_only(sql) {
+            return async { Err(_denied()) }.boxed();
+        }
+        self.0.describe(sql)
+    }
+}
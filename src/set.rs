@@ -0,0 +1,67 @@
+//! `SET('a','b','c')` columns, read and written as their comma separated text.
+//!
+//! Decode a column into `SetValue`, or split the text with `parse_set`. Bind a `SetValue` or
+//! the `set_arg` of a slice:
+//!
+//! ```ignore
+//! mysql::exec(&mut conn, mysql::query("UPDATE post SET tags = ? WHERE id = ?").bind(set::set_arg(&["news", "tech"])?).bind(id)).await?;
+//! let (tags,): (set::SetValue,) = mysql::exec_one(&mut conn, mysql::query_as("SELECT tags FROM post WHERE id = ?").bind(id)).await?;
+//! ```
+//!
+//! MySQL keeps a set, not a list: members come back in the order of the column definition
+//! whatever order they were written in, and a member written twice is stored once. The empty
+//! set is `''`. An unknown member fails the write in strict mode and is dropped with a warning
+//! otherwise. Members can't contain a comma. For a bitflags type, select `col + 0` to get the
+//! bitmask, bit `n` being the `n`th member of the definition.
+
+use sqlx::{decode::Decode, encode::{Encode, IsNull}, error::BoxDynError, mysql::{MySqlTypeInfo, MySqlValueRef}, MySql, Type};
+
+use crate::error::{DbError, ErrorKind, Result};
+
+
+/// The members of a `SET` value, in the order MySQL returned them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SetValue(pub Vec<String>);
+
+/// The members of the text of a `SET` value, none for `''`.
+pub fn parse_set(value: &str) -> Vec<String> {
+    if value.is_empty() {
+        return Vec::new();
+    }
+    value.split(',').map(|a| a.to_string()).collect()
+}
+
+/// The argument for a `SET` column holding `members`, fails if a member contains a comma.
+pub fn set_arg<S: AsRef<str>>(members: &[S]) -> Result<String> {
+    if let Some(member) = members.iter().find(|a| a.as_ref().contains(',')) {
+        let err = format!("set_arg failed: member {:?} contains a comma", member.as_ref());
+        tracing::error!("{}", err);
+        return Err(DbError::new(ErrorKind::Other, err));
+    }
+    Ok(members.iter().map(|a| a.as_ref()).collect::<Vec<_>>().join(","))
+}
+
+impl Type<MySql> for SetValue {
+    fn type_info() -> MySqlTypeInfo {
+        <str as Type<MySql>>::type_info()
+    }
+
+    // the server sends SET columns as strings with the SET flag
+    fn compatible(ty: &MySqlTypeInfo) -> bool {
+        <str as Type<MySql>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, MySql> for SetValue {
+    fn decode(value: MySqlValueRef<'r>) -> std::result::Result<Self, BoxDynError> {
+        let text = <&str as Decode<MySql>>::decode(value)?;
+        Ok(SetValue(parse_set(text)))
+    }
+}
+
+// members aren't checked for commas here, use set_arg to reject them
+impl Encode<'_, MySql> for SetValue {
+    fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+        <String as Encode<MySql>>::encode_by_ref(&self.0.join(","), buf)
+    }
+}
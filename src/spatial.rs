@@ -0,0 +1,97 @@
+//! `POINT` columns, behind the `spatial` feature. Only `POINT` is supported for now.
+//!
+//! Read a point with `ST_AsBinary(col)`, which returns standard WKB, into `Point` or decode the
+//! bytes with `decode_wkb_point`. Write one with `point_from_text_sql` and a `point_wkt` argument:
+//!
+//! ```ignore
+//! let sql = format!("INSERT INTO shop (name, loc) VALUES (?, {})", spatial::point_from_text_sql(4326));
+//! mysql::exec(&mut conn, mysql::query(&sql).bind(name).bind(spatial::point_wkt(lat, lng))).await?;
+//! let (loc,): (spatial::Point,) = mysql::exec_one(&mut conn, mysql::query_as("SELECT ST_AsBinary(loc) FROM shop")).await?;
+//! ```
+//!
+//! SRID: a point is stored with the SRID it was created with and a column declared with
+//! `SRID 4326` rejects any other. For SRID 4326 MySQL 8 reads WKT and writes WKB in the
+//! latitude-longitude axis order of that SRS, so `point_wkt(lat, lng)` and `Point { lat, lng }`
+//! line up. For SRID 0 (the default, a flat plane) the order is plain `x y`, use `lat` for `x`
+//! consistently. The raw column value without `ST_AsBinary` is MySQL's internal format, a 4 byte
+//! SRID before the WKB. `decode_wkb_point` accepts it too, but its axis order doesn't follow the
+//! SRS, prefer `ST_AsBinary` for geographic SRIDs.
+
+use sqlx::{decode::Decode, error::BoxDynError, mysql::{MySqlTypeInfo, MySqlValueRef}, MySql, Type};
+
+use crate::error::{DbError, ErrorKind, Result};
+
+
+/// A decoded `POINT`, `lat` / `lng` are the first / second coordinate, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Point {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// The two coordinates of a WKB `POINT`, or of MySQL's internal format with the SRID prefix.
+pub fn decode_wkb_point(bytes: &[u8]) -> Result<(f64, f64)> {
+    match _decode(bytes) {
+        Ok(a) => Ok(a),
+        Err(err) => {
+            let err = format!("decode_wkb_point failed: {}", err);
+            tracing::error!("{}", err);
+            return Err(DbError::new(ErrorKind::Other, err));
+        }
+    }
+}
+
+/// `ST_PointFromText(?, srid)`, to put in place of the value in an insert or update.
+pub fn point_from_text_sql(srid: u32) -> String {
+    format!("ST_PointFromText(?, {})", srid)
+}
+
+/// The WKT argument for `point_from_text_sql`, `POINT(lat lng)`.
+pub fn point_wkt(lat: f64, lng: f64) -> String {
+    format!("POINT({} {})", lat, lng)
+}
+
+impl Type<MySql> for Point {
+    fn type_info() -> MySqlTypeInfo {
+        <&[u8] as Type<MySql>>::type_info()
+    }
+
+    fn compatible(ty: &MySqlTypeInfo) -> bool {
+        <&[u8] as Type<MySql>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, MySql> for Point {
+    fn decode(value: MySqlValueRef<'r>) -> std::result::Result<Self, BoxDynError> {
+        let bytes = <&[u8] as Decode<MySql>>::decode(value)?;
+        let (lat, lng) = _decode(bytes)?;
+        Ok(Point { lat, lng })
+    }
+}
+
+fn _decode(bytes: &[u8]) -> std::result::Result<(f64, f64), String> {
+    // 21 bytes of WKB: byte order, u32 type, two f64, with a 4 byte SRID first in MySQL's format
+    let wkb = match bytes.len() {
+        21 => bytes,
+        25 => &bytes[4..],
+        n => return Err(format!("expected a 21 byte WKB POINT, got {} bytes", n)),
+    };
+    let little = match wkb[0] {
+        0 => false,
+        1 => true,
+        b => return Err(format!("invalid WKB byte order {}", b)),
+    };
+    let u32_at = |i: usize| {
+        let b: [u8; 4] = wkb[i..i + 4].try_into().unwrap();
+        if little { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    };
+    let f64_at = |i: usize| {
+        let b: [u8; 8] = wkb[i..i + 8].try_into().unwrap();
+        if little { f64::from_le_bytes(b) } else { f64::from_be_bytes(b) }
+    };
+    let geometry_type = u32_at(1);
+    if geometry_type != 1 {
+        return Err(format!("only POINT (WKB type 1) is supported, got type {}", geometry_type));
+    }
+    Ok((f64_at(5), f64_at(13)))
+}
@@ -0,0 +1,812 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static MAX_QUERY_LOG_LEN: AtomicUsize = AtomicUsize::new(2000);
+// random numbers at or below it sample a query, 0 samples none
+static QUERY_LOG_SAMPLE: AtomicU64 = AtomicU64::new(0);
+
+/// Caps the sql text this crate puts into tracing output, spans, error messages and logs,
+/// at `len` bytes, 2000 by default. Longer text is cut with a `...(truncated N chars)` suffix.
+///
+/// sqlx logs statements itself under the `sqlx::query` target, tune or silence that with
+/// `log_statements` / `log_slow_statements` in `pool::setup_with_options`.
+pub fn set_max_query_log_len(len: usize) {
+    MAX_QUERY_LOG_LEN.store(len, Ordering::Relaxed);
+}
+
+/// `text` cut to the `set_max_query_log_len` limit, on a char boundary.
+pub fn truncate_for_log(text: &str) -> Cow<'_, str> {
+    let max = MAX_QUERY_LOG_LEN.load(Ordering::Relaxed);
+    if text.len() <= max {
+        return Cow::Borrowed(text);
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated = text[end..].chars().count();
+    Cow::Owned(format!("{}...(truncated {} chars)", &text[..end], truncated))
+}
+
+/// Logs the sql and time of a random `rate` of the queries of the `mysql` helpers at `info`,
+/// e.g. `0.01` for 1%, with literals replaced by `?`. `0` (default) logs none, `1` every query.
+/// `MYSQL.QUERY_LOG_SAMPLE_RATE` sets it at setup.
+pub fn set_query_log_sample_rate(rate: f64) {
+    let threshold = if rate.is_nan() || rate <= 0.0 {
+        0
+    } else if rate >= 1.0 {
+        u64::MAX
+    } else {
+        (rate * u64::MAX as f64) as u64
+    };
+    QUERY_LOG_SAMPLE.store(threshold, Ordering::Relaxed);
+}
+
+pub(crate) fn sample_query() -> bool {
+    let threshold = QUERY_LOG_SAMPLE.load(Ordering::Relaxed);
+    threshold != 0 && crate::pool::next_rand() <= threshold
+}
+
+/// Declares a module of column name constants, so queries built at runtime reference
+/// `user_columns::NAME` instead of a `"naem"` string that only fails when decoding.
+///
+/// ```ignore
+/// qx_rs_server_sqlx::columns! {
+///     pub mod user_columns {
+///         ID = "id",
+///         NAME = "name",
+///     }
+/// }
+///
+/// let name: String = row.try_get(user_columns::NAME)?;
+/// let sql = format!("SELECT {} FROM user", user_columns::ALL.join(", "));
+/// ```
+///
+/// `ALL` lists every column in declaration order.
+#[macro_export]
+macro_rules! columns {
+    ($(#[$meta:meta])* $vis:vis mod $module:ident { $($(#[$column_meta:meta])* $name:ident = $column:literal),* $(,)? }) => {
+        $(#[$meta])*
+        $vis mod $module {
+            $(
+                $(#[$column_meta])*
+                pub const $name: &str = $column;
+            )*
+            pub const ALL: &[&str] = &[$($column),*];
+        }
+    };
+}
+
+/// Defines a struct and implements `insert_sql()` and `bind()` for it, the write side of
+/// `FromRow`, so a row is inserted without spelling out the columns and binding each field by hand.
+///
+/// ```ignore
+/// qx_rs_server_sqlx::insertable! {
+///     "user" =>
+///     #[derive(sqlx::FromRow)]
+///     pub struct User {
+///         #[insertable(skip)]
+///         pub id: i64,
+///         pub name: String,
+///         #[sqlx(rename = "email_address")]
+///         pub email: String,
+///         #[sqlx(skip)]
+///         pub cached_score: i64,
+///     }
+/// }
+///
+/// // INSERT INTO user (`name`, `email_address`) VALUES (?, ?)
+/// mysql::exec(&mut conn, user.bind(mysql::query(User::insert_sql()))).await?;
+/// ```
+///
+/// Columns are the fields in declaration order, named like `FromRow` reads them: the field name
+/// or its `#[sqlx(rename = "..")]`. `#[sqlx(skip)]` fields are left out, and so are fields
+/// marked `#[insertable(skip)]`, e.g. an `AUTO_INCREMENT` id that `FromRow` still reads.
+/// `#[sqlx(rename_all)]`, `#[sqlx(flatten)]` and `#[sqlx(json)]` fail to compile, the columns
+/// they stand for aren't known to the macro. Fields are bound by reference, the insert borrows
+/// the row.
+#[macro_export]
+macro_rules! insertable {
+    ($table:literal => $(#[$($attr:tt)*])* $vis:vis struct $name:ident { $($body:tt)* }) => {
+        $($crate::__insertable_struct_attr!($($attr)*);)*
+        $crate::__insertable_fields!([$table] [$name] [$(#[$($attr)*])* $vis struct $name] [] [] $($body)*);
+    };
+}
+
+/// Implements `FromRow` for a struct read from columns named `prefix` + field, the nested side
+/// of a join selected into one row, e.g. `address_city` for the `city` of an `Address`.
+///
+/// ```ignore
+/// qx_rs_server_sqlx::prefixed_row!(Address => "address_" { city, street, zip });
+///
+/// #[derive(sqlx::FromRow)]
+/// struct Order {
+///     id: i64,
+///     #[sqlx(flatten)]
+///     address: Address,
+/// }
+/// ```
+///
+/// Every field of the struct is listed, each decodes into its own type. The prefix belongs to the
+/// type, so two nested values of the same type in one row, such as a billing and a shipping
+/// address, need a newtype each.
+#[macro_export]
+macro_rules! prefixed_row {
+    ($ty:ty => $prefix:literal { $($field:ident),* $(,)? }) => {
+        impl<'r> ::sqlx::FromRow<'r, ::sqlx::mysql::MySqlRow> for $ty {
+            fn from_row(row: &'r ::sqlx::mysql::MySqlRow) -> ::std::result::Result<Self, ::sqlx::Error> {
+                Ok(Self {
+                    $($field: ::sqlx::Row::try_get(row, concat!($prefix, stringify!($field)))?,)*
+                })
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __insertable_struct_attr {
+    (sqlx($($inner:tt)*)) => { $crate::__insertable_struct_attr!(@sqlx $($inner)*); };
+    (@sqlx rename_all $($rest:tt)*) => { compile_error!("insertable! doesn't support #[sqlx(rename_all)], rename the fields one by one"); };
+    (@sqlx $first:tt $($rest:tt)*) => { $crate::__insertable_struct_attr!(@sqlx $($rest)*); };
+    (@sqlx) => {};
+    ($($other:tt)*) => {};
+}
+
+// munches the fields of an insertable! struct one by one: `[table] [name] [struct head]
+// [fields] [columns] rest`, a column is `([auto] field)`, `([rename "name"] field)` or
+// `([skip] field)`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __insertable_fields {
+    ([$table:literal] [$name:ident] [$($head:tt)*] [$($fields:tt)*] [$(([$($column:tt)*] $field:ident))*]) => {
+        $($head)* { $($fields)* }
+
+        impl $name {
+            pub fn insert_sql() -> &'static str {
+                static SQL: ::std::sync::OnceLock<::std::string::String> = ::std::sync::OnceLock::new();
+                SQL.get_or_init(|| $crate::sql::__insert_sql($table, &[$($crate::__insertable_column!([$($column)*] $field)),*]))
+            }
+
+            pub fn bind<'q>(&'q self, query: ::sqlx::query::Query<'q, ::sqlx::MySql, <::sqlx::MySql as ::sqlx::database::HasArguments<'q>>::Arguments>) -> ::sqlx::query::Query<'q, ::sqlx::MySql, <::sqlx::MySql as ::sqlx::database::HasArguments<'q>>::Arguments> {
+                $(let query = $crate::__insertable_bind!([$($column)*] query &self.$field);)*
+                query
+            }
+        }
+    };
+    ([$table:literal] [$name:ident] [$($head:tt)*] [$($fields:tt)*] [$($columns:tt)*] $($rest:tt)+) => {
+        $crate::__insertable_field!([$table] [$name] [$($head)*] [$($fields)*] [$($columns)*] [] [auto] $($rest)+);
+    };
+}
+
+// the attributes of one field, `[kept attributes] [column]` after the state of __insertable_fields
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __insertable_field {
+    (@sqlx $state:tt $kept:tt $column:tt [] $($rest:tt)*) => {
+        $crate::__insertable_field!(@state $state $kept $column $($rest)*);
+    };
+    (@sqlx $state:tt $kept:tt $column:tt [skip $($inner:tt)*] $($rest:tt)*) => {
+        $crate::__insertable_field!(@sqlx $state $kept [skip] [$($inner)*] $($rest)*);
+    };
+    (@sqlx $state:tt $kept:tt [skip] [rename = $renamed:literal $($inner:tt)*] $($rest:tt)*) => {
+        $crate::__insertable_field!(@sqlx $state $kept [skip] [$($inner)*] $($rest)*);
+    };
+    (@sqlx $state:tt $kept:tt $column:tt [rename = $renamed:literal $($inner:tt)*] $($rest:tt)*) => {
+        $crate::__insertable_field!(@sqlx $state $kept [rename $renamed] [$($inner)*] $($rest)*);
+    };
+    (@sqlx $state:tt $kept:tt $column:tt [flatten $($inner:tt)*] $($rest:tt)*) => {
+        compile_error!("insertable! doesn't support #[sqlx(flatten)], list the nested columns in the struct");
+    };
+    (@sqlx $state:tt $kept:tt $column:tt [json $($inner:tt)*] $($rest:tt)*) => {
+        compile_error!("insertable! doesn't support #[sqlx(json)], use a sqlx::types::Json field instead");
+    };
+    (@sqlx $state:tt $kept:tt $column:tt [$first:tt $($inner:tt)*] $($rest:tt)*) => {
+        $crate::__insertable_field!(@sqlx $state $kept $column [$($inner)*] $($rest)*);
+    };
+    (@state [$($state:tt)*] [$($kept:tt)*] [$($column:tt)*] $($rest:tt)*) => {
+        $crate::__insertable_field!($($state)* [$($kept)*] [$($column)*] $($rest)*);
+    };
+    ($table:tt $name:tt $head:tt $fields:tt $columns:tt [$($kept:tt)*] $column:tt #[insertable(skip)] $($rest:tt)*) => {
+        $crate::__insertable_field!($table $name $head $fields $columns [$($kept)*] [skip] $($rest)*);
+    };
+    ($table:tt $name:tt $head:tt $fields:tt $columns:tt [$($kept:tt)*] $column:tt #[sqlx($($inner:tt)*)] $($rest:tt)*) => {
+        $crate::__insertable_field!(@sqlx [$table $name $head $fields $columns] [$($kept)* #[sqlx($($inner)*)]] $column [$($inner)*] $($rest)*);
+    };
+    ($table:tt $name:tt $head:tt $fields:tt $columns:tt [$($kept:tt)*] $column:tt #[$($attr:tt)*] $($rest:tt)*) => {
+        $crate::__insertable_field!($table $name $head $fields $columns [$($kept)* #[$($attr)*]] $column $($rest)*);
+    };
+    ($table:tt $name:tt $head:tt [$($fields:tt)*] [$($columns:tt)*] [$($kept:tt)*] $column:tt $vis:vis $field:ident : $ty:ty $(, $($rest:tt)*)?) => {
+        $crate::__insertable_fields!($table $name $head [$($fields)* $($kept)* $vis $field: $ty,] [$($columns)* ($column $field)] $($($rest)*)?);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __insertable_column {
+    ([auto] $field:ident) => { ::std::option::Option::Some(stringify!($field)) };
+    ([rename $column:literal] $field:ident) => { ::std::option::Option::Some($column) };
+    ([skip] $field:ident) => { ::std::option::Option::None };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __insertable_bind {
+    ([skip] $query:ident $value:expr) => { $query };
+    ([$($column:tt)*] $query:ident $value:expr) => { $query.bind($value) };
+}
+
+// the INSERT of insertable!, `None` for a skipped field
+#[doc(hidden)]
+pub fn __insert_sql(table: &str, columns: &[Option<&str>]) -> String {
+    let columns: Vec<String> = columns.iter().flatten().map(|a| format!("`{}`", a)).collect();
+    format!("INSERT INTO {} ({}) VALUES ({})", table, columns.join(", "), vec!["?"; columns.len()].join(", "))
+}
+
+/// The first keyword of `sql`, skipping whitespace, comments and opening parentheses.
+///
+/// The content of `/*! ... */` comments is run by MySQL, so it is read as sql and not skipped.
+pub fn leading_keyword(sql: &str) -> &str {
+    let (start, end) = _leading_keyword(sql);
+    &sql[start..end]
+}
+
+fn _leading_keyword(sql: &str) -> (usize, usize) {
+    let b = sql.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            c if c.is_ascii_whitespace() || c == b'(' => i += 1,
+            b'#' => i = _skip_line(b, i),
+            b'-' if _is_line_comment(b, i) => i = _skip_line(b, i),
+            b'/' if b.get(i + 1) == Some(&b'*') && b.get(i + 2) == Some(&b'!') => {
+                i += 3;
+                while i < b.len() && b[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            b'/' if b.get(i + 1) == Some(&b'*') => i = _skip_block(b, i),
+            _ => break,
+        }
+    }
+    let start = i;
+    while i < b.len() && (b[i].is_ascii_alphabetic() || b[i] == b'_') {
+        i += 1;
+    }
+    (start, i)
+}
+
+// statements that commit the open transaction before they run, see causes_implicit_commit
+const IMPLICIT_COMMIT_KEYWORDS: &[&str] = &[
+    "ALTER", "ANALYZE", "BEGIN", "CACHE", "CHECK", "CREATE", "DROP", "FLUSH", "GRANT", "INSTALL",
+    "LOCK", "OPTIMIZE", "RENAME", "REPAIR", "RESET", "REVOKE", "START", "TRUNCATE", "UNINSTALL", "UNLOCK",
+];
+
+/// Whether MySQL commits the open transaction when running `sql`: DDL such as `CREATE`,
+/// `ALTER`, `DROP` and `TRUNCATE`, account statements like `GRANT` and `SET PASSWORD`, table
+/// maintenance, `LOCK TABLES` and a nested `BEGIN`. `CREATE` / `DROP TEMPORARY TABLE` don't commit and aren't counted.
+pub fn causes_implicit_commit(sql: &str) -> bool {
+    let (start, end) = _leading_keyword(sql);
+    let keyword = &sql[start..end];
+    let next = leading_keyword(&sql[end..]);
+    if keyword.eq_ignore_ascii_case("SET") {
+        return next.eq_ignore_ascii_case("PASSWORD");
+    }
+    if !IMPLICIT_COMMIT_KEYWORDS.iter().any(|a| keyword.eq_ignore_ascii_case(a)) {
+        return false;
+    }
+    if keyword.eq_ignore_ascii_case("CREATE") || keyword.eq_ignore_ascii_case("DROP") {
+        return !next.eq_ignore_ascii_case("TEMPORARY");
+    }
+    // START SLAVE / REPLICA don't commit
+    if keyword.eq_ignore_ascii_case("START") {
+        return next.eq_ignore_ascii_case("TRANSACTION");
+    }
+    true
+}
+
+/// `sql` with the optimizer hint `hint` right after its leading `SELECT`, e.g.
+/// `SELECT /*+ MAX_EXECUTION_TIME(500) */ id FROM t` for `MAX_EXECUTION_TIME(500)`, `None` when
+/// `sql` doesn't start with `SELECT`. MySQL reads hints only right after the keyword.
+pub fn with_optimizer_hint(sql: &str, hint: &str) -> Option<String> {
+    let (start, end) = _leading_keyword(sql);
+    if !sql[start..end].eq_ignore_ascii_case("SELECT") {
+        return None;
+    }
+    Some(format!("{} /*+ {} */{}", &sql[..end], hint, &sql[end..]))
+}
+
+/// Whether `sql` is a single `SELECT`, `SHOW`, `EXPLAIN` or `WITH ... SELECT` statement.
+///
+/// sqlx connects with `MULTI_STATEMENTS`, so any `;` outside literals and comments fails the
+/// check, a trailing one too. `EXPLAIN ANALYZE` runs the statement it explains and fails, as
+/// does a `WITH` whose statement after the common table expressions isn't a `SELECT`. The
+/// content of `/*! ... */` comments is run by MySQL, so it is read as sql.
+///
+/// Locking reads fail too, `FOR UPDATE`, `FOR SHARE` and `LOCK IN SHARE MODE` take row locks,
+/// and any `INTO` fails as it writes a file with `OUTFILE` / `DUMPFILE` or sets a variable.
+pub fn is_read_only(sql: &str) -> bool {
+    let tokens = _tokens(sql);
+    if tokens.contains(&";") || _locks_or_writes(&tokens) {
+        return false;
+    }
+    let mut rest = tokens.iter().copied().skip_while(|t| *t == "(");
+    let keyword = rest.next().unwrap_or("");
+    if keyword.eq_ignore_ascii_case("SELECT") || keyword.eq_ignore_ascii_case("SHOW") {
+        return true;
+    }
+    if keyword.eq_ignore_ascii_case("EXPLAIN") {
+        // ANALYZE is reserved, it can't be a bare name in the explained statement
+        return !rest.any(|t| t.eq_ignore_ascii_case("ANALYZE"));
+    }
+    if keyword.eq_ignore_ascii_case("WITH") {
+        return _with_statement(rest).is_some_and(|t| t.eq_ignore_ascii_case("SELECT"));
+    }
+    false
+}
+
+// INTO, FOR, LOCK and IN are reserved words, as bare tokens they can't be names
+fn _locks_or_writes(tokens: &[&str]) -> bool {
+    tokens.iter().enumerate().any(|(i, t)| {
+        let next = tokens.get(i + 1).copied().unwrap_or("");
+        t.eq_ignore_ascii_case("INTO")
+            || (t.eq_ignore_ascii_case("FOR") && (next.eq_ignore_ascii_case("UPDATE") || next.eq_ignore_ascii_case("SHARE")))
+            || (t.eq_ignore_ascii_case("LOCK") && next.eq_ignore_ascii_case("IN"))
+    })
+}
+
+// the first token of the statement after the common table expressions of a `WITH`
+fn _with_statement<'a>(tokens: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut depth = 0usize;
+    let mut prev_as = false;
+    let mut in_body = false;
+    let mut after_body = false;
+    for t in tokens {
+        if depth == 0 && after_body {
+            if t != "," {
+                return Some(t);
+            }
+            after_body = false;
+            continue;
+        }
+        match t {
+            "(" => {
+                if depth == 0 {
+                    in_body = prev_as;
+                }
+                depth += 1;
+            }
+            ")" => {
+                depth = depth.checked_sub(1)?;
+                after_body = depth == 0 && in_body;
+            }
+            _ => {}
+        }
+        if depth == 0 {
+            prev_as = t.eq_ignore_ascii_case("AS");
+        }
+    }
+    None
+}
+
+// words, quoted literals and names, and single punctuation bytes of `sql`, without whitespace
+// and comments
+fn _tokens(sql: &str) -> Vec<&str> {
+    let b = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let start = i;
+        match b[i] {
+            c if c.is_ascii_whitespace() => i += 1,
+            b'#' => i = _skip_line(b, i),
+            b'-' if _is_line_comment(b, i) => i = _skip_line(b, i),
+            b'/' if b.get(i + 1) == Some(&b'*') && b.get(i + 2) == Some(&b'!') => {
+                i += 3;
+                while i < b.len() && b[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            b'/' if b.get(i + 1) == Some(&b'*') => i = _skip_block(b, i),
+            q @ (b'\'' | b'"' | b'`') => {
+                i = _skip_quoted(b, i, q);
+                tokens.push(&sql[start..i]);
+            }
+            c if _is_ident(c) || c.is_ascii_digit() => {
+                while i < b.len() && (_is_ident(b[i]) || b[i].is_ascii_digit()) {
+                    i += 1;
+                }
+                tokens.push(&sql[start..i]);
+            }
+            _ => {
+                i += 1;
+                tokens.push(&sql[start..i]);
+            }
+        }
+    }
+    tokens
+}
+
+/// `sql` with string and numeric literals replaced by `?`, comments dropped and whitespace collapsed,
+/// so it can be logged or used as a span attribute without leaking values.
+pub fn sanitize(sql: &str) -> String {
+    let b = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut space = false;
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            c if c.is_ascii_whitespace() => {
+                space = true;
+                i += 1;
+            }
+            b'#' => {
+                space = true;
+                i = _skip_line(b, i);
+            }
+            b'-' if _is_line_comment(b, i) => {
+                space = true;
+                i = _skip_line(b, i);
+            }
+            b'/' if b.get(i + 1) == Some(&b'*') => {
+                space = true;
+                i = _skip_block(b, i);
+            }
+            q @ (b'\'' | b'"') => {
+                _push(&mut out, &mut space, "?");
+                i = _skip_quoted(b, i, q);
+            }
+            b'`' => {
+                let end = _skip_quoted(b, i, b'`');
+                _push(&mut out, &mut space, &sql[i..end]);
+                i = end;
+            }
+            c if c.is_ascii_digit() => {
+                while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'.') {
+                    i += 1;
+                }
+                _push(&mut out, &mut space, "?");
+            }
+            c if _is_ident(c) => {
+                let start = i;
+                while i < b.len() && (_is_ident(b[i]) || b[i].is_ascii_digit()) {
+                    i += 1;
+                }
+                _push(&mut out, &mut space, &sql[start..i]);
+            }
+            _ => {
+                let start = i;
+                i += 1;
+                _push(&mut out, &mut space, &sql[start..i]);
+            }
+        }
+    }
+    out
+}
+
+/// The shape of `sql` for grouping metrics per query: `sanitize` with every `IN (?, ?, ...)`
+/// list collapsed to `IN (?)`, so queries differing only in literals or in the length of an
+/// `IN` list get the same fingerprint.
+pub fn fingerprint(sql: &str) -> String {
+    let sanitized = sanitize(sql);
+    let b = sanitized.as_bytes();
+    let mut out = String::with_capacity(sanitized.len());
+    let mut i = 0;
+    let mut start = 0;
+    while i < b.len() {
+        if (b[i] == b'I' || b[i] == b'i') && (i == 0 || !_is_ident(b[i - 1]) && !b[i - 1].is_ascii_digit()) {
+            if let Some(end) = _in_list_end(b, i) {
+                out.push_str(&sanitized[start..i]);
+                out.push_str("IN (?)");
+                i = end;
+                start = end;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    out.push_str(&sanitized[start..]);
+    out
+}
+
+// end of `IN (?, ?, ...)` starting at `i` in sanitized sql, spaces are single after sanitize
+fn _in_list_end(b: &[u8], i: usize) -> Option<usize> {
+    if !b.get(i + 1).is_some_and(|c| *c == b'N' || *c == b'n') {
+        return None;
+    }
+    let mut j = i + 2;
+    if b.get(j) == Some(&b' ') {
+        j += 1;
+    }
+    if b.get(j) != Some(&b'(') {
+        return None;
+    }
+    j += 1;
+    loop {
+        if b.get(j) == Some(&b' ') {
+            j += 1;
+        }
+        if b.get(j) != Some(&b'?') {
+            return None;
+        }
+        j += 1;
+        if b.get(j) == Some(&b' ') {
+            j += 1;
+        }
+        match b.get(j) {
+            Some(b',') => j += 1,
+            Some(b')') => return Some(j + 1),
+            _ => return None,
+        }
+    }
+}
+
+/// The byte range of the `IN (?)` of `sql` when that `?` is its only placeholder, for
+/// `mysql::fetch_in`. Any case and spacing of `IN ( ? )` matches, a `?` in a literal, a quoted
+/// name or a comment is no placeholder. The content of `/*! ... */` comments is run by MySQL, so
+/// it is read as sql.
+pub(crate) fn in_list_placeholder(sql: &str) -> Option<(usize, usize)> {
+    let b = sql.as_bytes();
+    let mut placeholders = 0;
+    let mut found = None;
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'#' => i = _skip_line(b, i),
+            b'-' if _is_line_comment(b, i) => i = _skip_line(b, i),
+            b'/' if b.get(i + 1) == Some(&b'*') && b.get(i + 2) == Some(&b'!') => i += 3,
+            b'/' if b.get(i + 1) == Some(&b'*') => i = _skip_block(b, i),
+            q @ (b'\'' | b'"' | b'`') => i = _skip_quoted(b, i, q),
+            b'?' => {
+                placeholders += 1;
+                i += 1;
+            }
+            c if _is_ident(c) || c.is_ascii_digit() => {
+                let start = i;
+                while i < b.len() && (_is_ident(b[i]) || b[i].is_ascii_digit()) {
+                    i += 1;
+                }
+                if sql[start..i].eq_ignore_ascii_case("IN") {
+                    if let Some(end) = _in_placeholder_end(b, i) {
+                        found = Some((start, end));
+                        placeholders += 1;
+                        i = end;
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    match found {
+        Some(range) if placeholders == 1 => Some(range),
+        _ => None,
+    }
+}
+
+// end of `( ? )` after an `IN` ending at `i`, with any whitespace
+fn _in_placeholder_end(b: &[u8], mut i: usize) -> Option<usize> {
+    for expected in [b'(', b'?', b')'] {
+        while b.get(i).is_some_and(|c| c.is_ascii_whitespace()) {
+            i += 1;
+        }
+        if b.get(i) != Some(&expected) {
+            return None;
+        }
+        i += 1;
+    }
+    Some(i)
+}
+
+fn _push(out: &mut String, space: &mut bool, s: &str) {
+    if *space && !out.is_empty() {
+        out.push(' ');
+    }
+    *space = false;
+    out.push_str(s);
+}
+
+// letters, `_`, `$` and every byte of a non-ascii char, so multi-byte chars are never split
+fn _is_ident(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+fn _is_line_comment(b: &[u8], i: usize) -> bool {
+    if b.get(i + 1) != Some(&b'-') {
+        return false;
+    }
+    match b.get(i + 2) {
+        Some(c) => c.is_ascii_whitespace(),
+        None => true,
+    }
+}
+
+fn _skip_line(b: &[u8], mut i: usize) -> usize {
+    while i < b.len() && b[i] != b'\n' {
+        i += 1;
+    }
+    i
+}
+
+fn _skip_block(b: &[u8], mut i: usize) -> usize {
+    i += 2;
+    while i < b.len() {
+        if b[i] == b'*' && b.get(i + 1) == Some(&b'/') {
+            return i + 2;
+        }
+        i += 1;
+    }
+    i
+}
+
+// index after the closing quote, `\` escapes and doubled quotes stay inside
+fn _skip_quoted(b: &[u8], mut i: usize, quote: u8) -> usize {
+    i += 1;
+    while i < b.len() {
+        if b[i] == b'\\' && quote != b'`' {
+            i += 2;
+        } else if b[i] == quote {
+            if b.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    b.len()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::insertable! {
+        "user" =>
+        #[derive(sqlx::FromRow)]
+        struct InsertUser {
+            #[insertable(skip)]
+            id: i64,
+            /// the display name
+            name: String,
+            #[sqlx(rename = "email_address")]
+            email: String,
+            #[sqlx(default, skip)]
+            score: i64,
+            #[sqlx(default)]
+            note: Option<String>,
+        }
+    }
+
+    #[test]
+    fn fingerprint_collapses_in_lists() {
+        let expected = "SELECT * FROM t WHERE id IN (?) AND org = ?";
+        assert_eq!(fingerprint("SELECT * FROM t WHERE id IN (1, 2, 3) AND org = 7"), expected);
+        assert_eq!(fingerprint("SELECT * FROM t WHERE id IN (?) AND org = ?"), expected);
+        assert_eq!(fingerprint("SELECT * FROM t WHERE id in(?,?) AND org = ?"), "SELECT * FROM t WHERE id IN (?) AND org = ?");
+        assert_eq!(fingerprint("SELECT * FROM t WHERE name IN ('a', 'b')"), fingerprint("SELECT * FROM t WHERE name IN ('c')"));
+        // not an IN list
+        assert_eq!(fingerprint("SELECT * FROM t WHERE id IN (SELECT id FROM u)"), "SELECT * FROM t WHERE id IN (SELECT id FROM u)");
+        assert_eq!(fingerprint("SELECT join_id FROM t WHERE pin (1)"), "SELECT join_id FROM t WHERE pin (?)");
+    }
+
+    #[test]
+    fn fingerprint_ignores_literals() {
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE name = 'bob' AND age > 30 AND score < 1.5e3"),
+            fingerprint("SELECT * FROM t WHERE name = \"alice\" AND age > 7 AND score < 0.25"),
+        );
+        assert_eq!(fingerprint("SELECT * FROM t WHERE name = 'bob'"), "SELECT * FROM t WHERE name = ?");
+        // digits inside names stay
+        assert_eq!(fingerprint("SELECT col1 FROM t2"), "SELECT col1 FROM t2");
+        assert_eq!(fingerprint("SELECT `a 1` FROM t"), "SELECT `a 1` FROM t");
+    }
+
+    #[test]
+    fn fingerprint_handles_escaped_quotes() {
+        assert_eq!(fingerprint("SELECT * FROM t WHERE a = 'it''s' AND b = 1"), "SELECT * FROM t WHERE a = ? AND b = ?");
+        assert_eq!(fingerprint("SELECT * FROM t WHERE a = 'it\\'s' AND b = 1"), "SELECT * FROM t WHERE a = ? AND b = ?");
+        assert_eq!(fingerprint("SELECT * FROM t WHERE a = 'x IN (1, 2)'"), "SELECT * FROM t WHERE a = ?");
+    }
+
+    #[test]
+    fn fingerprint_drops_comments_and_collapses_whitespace() {
+        let expected = "SELECT * FROM t WHERE id = ?";
+        assert_eq!(fingerprint("SELECT *  FROM t\n\tWHERE id = 1"), expected);
+        assert_eq!(fingerprint("SELECT * /* user 42 */ FROM t WHERE id = 1 -- trailing"), expected);
+        assert_eq!(fingerprint("# report\nSELECT * FROM t WHERE id = 1"), expected);
+    }
+
+    #[test]
+    fn insertable_honors_sqlx_rename_and_skip() {
+        assert_eq!(InsertUser::insert_sql(), "INSERT INTO user (`name`, `email_address`, `note`) VALUES (?, ?, ?)");
+        let user = InsertUser { id: 1, name: "a".to_string(), email: "a@example.com".to_string(), score: 0, note: None };
+        let mut query = user.bind(sqlx::query(InsertUser::insert_sql()));
+        assert_eq!(sqlx::Execute::take_arguments(&mut query).unwrap().len(), 3);
+        assert_eq!((user.id, user.score), (1, 0));
+    }
+
+    #[test]
+    fn in_list_placeholder_matches_any_case_and_spacing() {
+        for sql in ["SELECT * FROM t WHERE id IN (?)", "select * from t where id in(?)", "SELECT * FROM t WHERE id In ( ? ) AND 1"] {
+            let (start, end) = in_list_placeholder(sql).unwrap();
+            assert!(sql[start..end].replace(' ', "").eq_ignore_ascii_case("IN(?)"), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn in_list_placeholder_skips_literals_and_comments() {
+        let sql = "SELECT * FROM t WHERE name <> '?' AND `a?` = \"?\" /* ? */ AND id IN (?) -- ?";
+        let (start, end) = in_list_placeholder(sql).unwrap();
+        assert_eq!(&sql[start..end], "IN (?)");
+        assert_eq!(in_list_placeholder("SELECT * FROM t WHERE name = 'IN (?)'"), None);
+    }
+
+    #[test]
+    fn in_list_placeholder_needs_exactly_one_placeholder() {
+        assert_eq!(in_list_placeholder("SELECT * FROM t WHERE id IN (?) AND org = ?"), None);
+        assert_eq!(in_list_placeholder("SELECT * FROM t WHERE id IN (?) /*! AND org = ? */"), None);
+        assert_eq!(in_list_placeholder("SELECT * FROM t WHERE id IN (?, ?)"), None);
+        assert_eq!(in_list_placeholder("SELECT * FROM t WHERE join_id = ?"), None);
+        assert_eq!(in_list_placeholder("SELECT * FROM t WHERE pin (?)"), None);
+    }
+
+    #[test]
+    fn is_read_only_accepts_single_reads() {
+        assert!(is_read_only("SELECT 1"));
+        assert!(is_read_only("  /* report */ select id FROM t WHERE name = ';'"));
+        assert!(is_read_only("(SELECT 1) UNION (SELECT 2)"));
+        assert!(is_read_only("SHOW TABLES"));
+        assert!(is_read_only("EXPLAIN SELECT * FROM t"));
+        assert!(is_read_only("SELECT 1 -- ; DROP TABLE t"));
+        assert!(is_read_only("SELECT `a;b` FROM t # ;"));
+    }
+
+    #[test]
+    fn is_read_only_rejects_writes() {
+        assert!(!is_read_only("UPDATE t SET a = 1"));
+        assert!(!is_read_only("/* SELECT */ DELETE FROM t"));
+        assert!(!is_read_only("/*!50000 DELETE */ FROM t"));
+        assert!(!is_read_only(""));
+    }
+
+    #[test]
+    fn is_read_only_rejects_multiple_statements() {
+        assert!(!is_read_only("SELECT 1; DROP TABLE t"));
+        assert!(!is_read_only("SELECT 1;"));
+        assert!(!is_read_only("SELECT 1 /*!; DROP TABLE t */"));
+        assert!(!is_read_only("SELECT 'it''s'; DELETE FROM t"));
+    }
+
+    #[test]
+    fn is_read_only_rejects_explain_analyze() {
+        assert!(!is_read_only("EXPLAIN ANALYZE DELETE t1 FROM t1 JOIN t2"));
+        assert!(!is_read_only("explain /* x */ analyze select 1"));
+        assert!(is_read_only("EXPLAIN SELECT `analyze` FROM t"));
+    }
+
+    #[test]
+    fn is_read_only_rejects_locking_reads_and_into() {
+        assert!(!is_read_only("SELECT * FROM t WHERE id = 1 FOR UPDATE"));
+        assert!(!is_read_only("select * from t for update nowait"));
+        assert!(!is_read_only("SELECT * FROM t FOR SHARE SKIP LOCKED"));
+        assert!(!is_read_only("SELECT * FROM t LOCK IN SHARE MODE"));
+        assert!(!is_read_only("WITH a AS (SELECT 1) SELECT * FROM a, t FOR UPDATE OF t"));
+        assert!(!is_read_only("SELECT * FROM t INTO OUTFILE '/tmp/t.csv'"));
+        assert!(!is_read_only("SELECT a FROM t INTO DUMPFILE '/tmp/a'"));
+        assert!(!is_read_only("SELECT COUNT(*) INTO @n FROM t"));
+        assert!(is_read_only("SELECT 'for update', `into` FROM t /* FOR UPDATE */"));
+        assert!(is_read_only("SHOW GRANTS FOR CURRENT_USER"));
+    }
+
+    #[test]
+    fn is_read_only_checks_the_statement_after_with() {
+        assert!(is_read_only("WITH a AS (SELECT 1) SELECT * FROM a"));
+        assert!(is_read_only("with recursive a (n) as (select 1 union all select n + 1 from a where n < 3), b as (select 2) select * from a, b"));
+        assert!(!is_read_only("WITH a AS (SELECT id FROM t) DELETE FROM t WHERE id IN (SELECT id FROM a)"));
+        assert!(!is_read_only("WITH a AS (SELECT 1) UPDATE t, a SET t.x = 1"));
+        assert!(!is_read_only("WITH a AS (SELECT ')') INSERT INTO t SELECT * FROM a"));
+        assert!(!is_read_only("WITH a AS (SELECT 1"));
+    }
+}
@@ -0,0 +1,251 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use futures::future::BoxFuture;
+use sqlx::database::HasArguments;
+use sqlx::pool::PoolConnection;
+use sqlx::query::Query;
+use sqlx::Execute;
+use sqlx::{mysql::{MySqlArguments, MySqlRow}, query::QueryAs, FromRow, MySql, Transaction};
+
+use crate::error::Result;
+
+use crate::{error, mysql, pool};
+
+
+static NEXT_TX_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) type CommitHook = Box<dyn FnOnce() + Send>;
+
+/// The transaction of `pool::get_trans` and `begin`, derefs to the sqlx `Transaction`.
+///
+/// It carries the id of the `tx.*` trace events and the hooks of `pool::on_commit`, and traces
+/// how it ends, `tx.commit` and `tx.rollback` for the explicit calls and `tx.drop` when it is
+/// dropped without either: the future holding it was cancelled, e.g. on a client disconnect,
+/// or returned early with `?`.
+///
+/// A dropped transaction is rolled back by sqlx. The `ROLLBACK` is queued on the connection
+/// and flushed by the ping the pool sends on every returned connection, a connection where
+/// that fails is closed instead of being reused, so none goes back to the pool mid-transaction.
+pub struct TransactionGuard<'c> {
+    trans: Option<Transaction<'c, MySql>>,
+    id: u64,
+    hooks: Mutex<Vec<CommitHook>>,
+    _task: pool::TaskTx,
+}
+
+/// Begins a transaction on `conn`, same as `pool::get_trans`.
+pub async fn begin(conn: &mut PoolConnection<MySql>) -> Result<TransactionGuard<'_>> {
+    pool::get_trans(conn).await
+}
+
+impl<'c> TransactionGuard<'c> {
+    pub(crate) fn new(trans: Transaction<'c, MySql>) -> Self {
+        TransactionGuard {
+            trans: Some(trans),
+            id: NEXT_TX_ID.fetch_add(1, Ordering::Relaxed),
+            hooks: Mutex::new(Vec::new()),
+            _task: pool::task_tx(),
+        }
+    }
+
+    /// The id of the `tx.begin`, `tx.commit`, `tx.rollback` and `tx.drop` events, see `pool::tx_span`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        pool::commit(self).await
+    }
+
+    pub async fn rollback(self) -> Result<()> {
+        pool::rollback(self).await
+    }
+
+    pub(crate) fn push_commit_hook(&self, hook: CommitHook) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    // the transaction, its id and its commit hooks, for ending it without the tx.drop event
+    pub(crate) fn end(mut self) -> (Transaction<'c, MySql>, u64, Vec<CommitHook>) {
+        let trans = self.trans.take().expect("transaction already ended");
+        let hooks = std::mem::take(self.hooks.get_mut().unwrap());
+        (trans, self.id, hooks)
+    }
+}
+
+impl<'c> Deref for TransactionGuard<'c> {
+    type Target = Transaction<'c, MySql>;
+
+    fn deref(&self) -> &Self::Target {
+        self.trans.as_ref().expect("transaction already ended")
+    }
+}
+
+impl<'c> DerefMut for TransactionGuard<'c> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.trans.as_mut().expect("transaction already ended")
+    }
+}
+
+impl<'c> Drop for TransactionGuard<'c> {
+    fn drop(&mut self) {
+        if self.trans.is_some() {
+            tracing::warn!(tx_id = self.id, panicking = std::thread::panicking(), "tx.drop");
+        }
+    }
+}
+
+// the mysql helpers, run on the connection the transaction holds. Debug builds warn when one
+// runs a statement that makes MySQL commit the transaction, see sql::causes_implicit_commit
+
+pub async fn tx_exec_arr<'q, T>(tx: &mut Transaction<'_, MySql>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    #[cfg(debug_assertions)]
+    _check_implicit_commit(sql_as.sql());
+    mysql::exec_arr(tx, sql_as).await
+}
+
+pub async fn tx_exec_one<'q, T>(tx: &mut Transaction<'_, MySql>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<T>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    #[cfg(debug_assertions)]
+    _check_implicit_commit(sql_as.sql());
+    mysql::exec_one(tx, sql_as).await
+}
+
+pub async fn tx_exec_opt_one<'q, T>(tx: &mut Transaction<'_, MySql>, sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Option<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    #[cfg(debug_assertions)]
+    _check_implicit_commit(sql_as.sql());
+    mysql::exec_opt_one(tx, sql_as).await
+}
+
+pub async fn tx_exec<'q>(tx: &mut Transaction<'_, MySql>, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<(u64, u64)>
+{
+    #[cfg(debug_assertions)]
+    _check_implicit_commit(sql.sql());
+    mysql::exec(tx, sql).await
+}
+
+pub async fn tx_exec_result<'q>(tx: &mut Transaction<'_, MySql>, sql: Query<'q, MySql, <MySql as HasArguments<'q>>::Arguments>) -> Result<mysql::ExecResult>
+{
+    #[cfg(debug_assertions)]
+    _check_implicit_commit(sql.sql());
+    mysql::exec_result(tx, sql).await
+}
+
+#[cfg(debug_assertions)]
+fn _check_implicit_commit(sql: &str) {
+    if crate::sql::causes_implicit_commit(sql) {
+        tracing::warn!("{} commits the open transaction implicitly, the statements before it can't be rolled back", crate::sql::truncate_for_log(&crate::sql::sanitize(sql)));
+    }
+}
+
+/// Runs `sql_as` with a shared lock on the rows it reads, `FOR SHARE` on MySQL 8.0.1+ and
+/// `LOCK IN SHARE MODE` on 5.7 and MariaDB, from the `SELECT VERSION()` of the connection the
+/// transaction runs on.
+///
+/// Shared locks let other transactions read and share-lock the rows too but block their
+/// `UPDATE`, `DELETE` and `FOR UPDATE` until this transaction ends, so the rows stay as read.
+/// The locks are held until commit or rollback, which is why this takes a transaction: under
+/// autocommit they would be released as soon as the statement ends. `sql_as` must be a `SELECT`
+/// without a locking clause of its own.
+pub async fn select_for_share<'q, T>(tx: &mut Transaction<'_, MySql>, mut sql_as: QueryAs<'q, MySql, T, MySqlArguments>) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, MySqlRow> + Send + Unpin,
+{
+    let version = mysql::server_version(tx).await?;
+    let sql = format!("{} {}", sql_as.sql(), mysql::share_lock_clause(&version));
+    let args = sql_as.take_arguments().unwrap_or_default();
+    mysql::query_as_with::<T, _>(tx, &sql, args).await
+}
+
+/// Runs `f` in a transaction on a connection of `which_database` and commits it, running it
+/// again in a new transaction when it or the commit fails with a deadlock, at most
+/// `max_attempts` times in total. Other errors roll back and are returned right away.
+///
+/// `f` gets the transaction and the attempt number, starting at 1, e.g. to raise
+/// `innodb_lock_wait_timeout` on later attempts. Work done outside the transaction is not
+/// undone by the rollback, so `f` must only have effects through `tx` to be safe to rerun.
+/// A `get_conn` of `which_database` inside `f` while every connection is in use fails at once
+/// as a potential self-deadlock, see `MYSQL.SELF_DEADLOCK`. Register work to do only once the
+/// transaction committed, such as publishing events, with `pool::on_commit(tx, ..)`.
+///
+/// ```ignore
+/// tx::run_in_transaction_retry(DEFAULT, 3, |tx, attempt| Box::pin(async move {
+///     tx::tx_exec(tx, mysql::query("UPDATE t SET n = n + 1 WHERE id = 1")).await
+/// })).await?;
+/// ```
+pub async fn run_in_transaction_retry<T, F>(which_database: &'static str, max_attempts: u32, mut f: F) -> Result<T>
+where
+    F: for<'t, 'c> FnMut(&'t mut TransactionGuard<'c>, u32) -> BoxFuture<'t, Result<T>>,
+{
+    let mut conn = pool::get_conn_from_database(which_database).await?;
+    let _hold = pool::hold_tx(which_database);
+    let mut attempt = 1;
+    loop {
+        let mut trans = begin(&mut conn).await?;
+        let res = match f(&mut trans, attempt).await {
+            Ok(v) => trans.commit().await.map(|_| v),
+            Err(err) => {
+                // already logged by rollback, the error of `f` is the one to report
+                let _ = trans.rollback().await;
+                Err(err)
+            }
+        };
+        match res {
+            Err(err) if attempt < max_attempts && error::is_deadlock(&err) => {
+                tracing::warn!("transaction deadlocked on attempt {} of {}, retrying", attempt, max_attempts);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Inserts `rows` in transactions of `batch_size` rows each, committing after every batch,
+/// and returns the total `insert_fn` reported as inserted.
+///
+/// `insert_fn` gets the transaction and the rows of one batch, e.g. to bind them into a
+/// multi-row `INSERT`. This gives up all-or-nothing atomicity for bounded transactions: each
+/// commit keeps the redo log and the lock time small, but when a batch fails the batches
+/// before it stay committed, the error log says how many rows that was.
+pub async fn batch_commit_insert<R, F>(which_database: &'static str, rows: &[R], batch_size: usize, mut insert_fn: F) -> Result<u64>
+where
+    R: Sync,
+    F: for<'t, 'c> FnMut(&'t mut TransactionGuard<'c>, &'t [R]) -> BoxFuture<'t, Result<u64>>,
+{
+    let mut conn = pool::get_conn_from_database(which_database).await?;
+    let _hold = pool::hold_tx(which_database);
+    let mut inserted = 0;
+    for batch in rows.chunks(batch_size.max(1)) {
+        let res = match begin(&mut conn).await {
+            Ok(mut trans) => match insert_fn(&mut trans, batch).await {
+                Ok(n) => trans.commit().await.map(|_| n),
+                Err(err) => {
+                    let _ = trans.rollback().await;
+                    Err(err)
+                }
+            },
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(n) => {
+                inserted += n;
+                tracing::debug!(inserted, total = rows.len(), "batch_commit_insert committed a batch");
+            }
+            Err(err) => {
+                tracing::error!("batch_commit_insert failed after {} committed rows", inserted);
+                return Err(err);
+            }
+        }
+    }
+    Ok(inserted)
+}
@@ -0,0 +1,28 @@
+// In a binary of its own, close_all closes the pools of every test of the binary.
+
+mod common;
+
+use std::time::Duration;
+
+use qx_rs_server_sqlx::pool;
+
+const WHICH: &str = "CLOSING";
+
+#[test]
+fn close_all_fails_waiting_acquires_as_shutting_down() {
+    common::run(|| async {
+        common::database_env(WHICH, &[("MAX_CONNECTS", "1")]);
+        pool::setup_database(WHICH).await.unwrap();
+        let held = pool::get_conn_from_database(WHICH).await.unwrap();
+        let waiting = tokio::spawn(pool::get_conn_from_database(WHICH));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // waits for the held connection, the waiting acquire fails right away
+        let close = tokio::spawn(pool::close_all());
+        let err = waiting.await.unwrap().unwrap_err();
+        assert!(err.message().contains("pool is shutting down"), "{}", err);
+        drop(held);
+        close.await.unwrap();
+        let err = pool::get_conn_from_database(WHICH).await.unwrap_err();
+        assert!(err.message().contains("pool is shutting down"), "{}", err);
+    });
+}
@@ -0,0 +1,52 @@
+// In a binary of its own, the `_migrations` table is shared by every run_migrations. The tests
+// only touch versions of their own and leave the other rows alone.
+
+mod common;
+
+use sqlx::MySqlConnection;
+
+use qx_rs_server_sqlx::{migrate, mysql, pool};
+
+const MIGRATIONS: &[(i64, &str)] = &[
+    (900001, "CREATE TABLE qx_test_migrated (id BIGINT PRIMARY KEY, name VARCHAR(16) NOT NULL)"),
+    (900002, "INSERT INTO qx_test_migrated VALUES (1, 'a')"),
+];
+
+async fn _clean(conn: &mut MySqlConnection) {
+    mysql::exec(&mut *conn, mysql::query("DROP TABLE IF EXISTS qx_test_migrated")).await.unwrap();
+    // fails before the first run_migrations created the table
+    let _ = mysql::exec(&mut *conn, mysql::query("DELETE FROM _migrations WHERE version IN (900001, 900002)")).await;
+}
+
+async fn _counts(conn: &mut MySqlConnection) -> (i64, i64) {
+    let rows = mysql::exec_scalar_or(&mut *conn, mysql::query_scalar("SELECT COUNT(*) FROM qx_test_migrated"), -1).await.unwrap();
+    let versions = mysql::exec_scalar_or(&mut *conn, mysql::query_scalar("SELECT COUNT(*) FROM _migrations WHERE version IN (900001, 900002)"), -1).await.unwrap();
+    (rows, versions)
+}
+
+#[test]
+fn concurrent_runners_apply_each_migration_once() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _clean(&mut conn).await;
+        let runners: Vec<_> = (0..2).map(|_| tokio::spawn(async {
+            let mut conn = pool::get_conn().await.unwrap();
+            migrate::run_migrations(&mut conn, MIGRATIONS).await
+        })).collect();
+        let mut results = Vec::new();
+        for runner in runners {
+            results.push(runner.await.unwrap());
+        }
+        // a migration applied twice fails on the CREATE or the duplicate id
+        let applied = _counts(&mut conn).await;
+        let rerun = migrate::run_migrations(&mut conn, MIGRATIONS).await;
+        let rerun_applied = _counts(&mut conn).await;
+        _clean(&mut conn).await;
+        for res in results {
+            res.unwrap();
+        }
+        assert_eq!(applied, (1, 2));
+        rerun.unwrap();
+        assert_eq!(rerun_applied, (1, 2));
+    });
+}
@@ -0,0 +1,259 @@
+mod common;
+
+use sqlx::mysql::MySqlArguments;
+use sqlx::MySqlConnection;
+
+use qx_rs_server_sqlx::error::ErrorKind;
+use qx_rs_server_sqlx::{mysql, pool};
+
+#[derive(sqlx::FromRow)]
+struct Flag {
+    id: i64,
+    enabled: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Item {
+    id: i64,
+    name: String,
+}
+
+// only ever fails to decode
+#[allow(dead_code)]
+#[derive(Debug, sqlx::FromRow)]
+struct Dated {
+    id: i64,
+    created_at: i64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, sqlx::FromRow)]
+struct ItemWithPrice {
+    id: i64,
+    name: String,
+    price: i64,
+}
+
+// a temporary table `table` on `conn` with the rows (1, 'a', 10), (2, 'b', 20) and (3, 'c', 10)
+// of `id`, `name` and `group_id`. Temporary tables stay with the pooled connection, each test
+// uses its own name and drops it before creating.
+async fn _items(conn: &mut MySqlConnection, table: &str) {
+    mysql::exec(&mut *conn, mysql::query(&format!("DROP TEMPORARY TABLE IF EXISTS {}", table))).await.unwrap();
+    mysql::exec(&mut *conn, mysql::query(&format!("CREATE TEMPORARY TABLE {} (id BIGINT PRIMARY KEY, name VARCHAR(16) NOT NULL, group_id BIGINT NOT NULL)", table))).await.unwrap();
+    mysql::exec(&mut *conn, mysql::query(&format!("INSERT INTO {} VALUES (1, 'a', 10), (2, 'b', 20), (3, 'c', 10)", table))).await.unwrap();
+}
+
+async fn _drop(conn: &mut MySqlConnection, table: &str) {
+    mysql::exec(&mut *conn, mysql::query(&format!("DROP TEMPORARY TABLE {}", table))).await.unwrap();
+}
+
+#[test]
+fn tinyint_1_decodes_to_bool() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("CREATE TEMPORARY TABLE qx_test_flag (id BIGINT PRIMARY KEY, enabled TINYINT(1) NOT NULL)")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("INSERT INTO qx_test_flag VALUES (1, ?), (2, ?), (3, 2)").bind(true).bind(false)).await.unwrap();
+        let flags = mysql::exec_arr(&mut conn, mysql::query_as::<Flag>("SELECT id, enabled FROM qx_test_flag ORDER BY id")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE qx_test_flag")).await.unwrap();
+        // a stored 2 is nonzero and reads back as true
+        let flags: Vec<(i64, bool)> = flags.iter().map(|f| (f.id, f.enabled)).collect();
+        assert_eq!(flags, [(1, true), (2, false), (3, true)]);
+    });
+}
+
+#[test]
+fn fetch_map_keys_rows_last_wins() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_map").await;
+        let items = mysql::fetch_map(&mut conn, mysql::query_as::<Item>("SELECT id, name FROM qx_test_map"), |a| a.id).await.unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[&2].name, "b");
+        let by_group = mysql::fetch_map(&mut conn, mysql::query_as::<Item>("SELECT group_id AS id, name FROM qx_test_map ORDER BY qx_test_map.id"), |a| a.id).await.unwrap();
+        assert_eq!(by_group.len(), 2);
+        assert_eq!(by_group[&10].name, "c");
+        _drop(&mut conn, "qx_test_map").await;
+    });
+}
+
+#[test]
+fn fetch_grouped_keeps_row_order() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_grouped").await;
+        let groups = mysql::fetch_grouped(&mut conn, mysql::query_as::<(i64, String)>("SELECT group_id, name FROM qx_test_grouped ORDER BY id"), |a| a.0).await.unwrap();
+        _drop(&mut conn, "qx_test_grouped").await;
+        assert_eq!(groups.len(), 2);
+        let names = |key: i64| groups[&key].iter().map(|a| a.1.as_str()).collect::<Vec<_>>();
+        assert_eq!(names(10), ["a", "c"]);
+        assert_eq!(names(20), ["b"]);
+    });
+}
+
+#[test]
+fn exec_scalar_or_defaults_when_absent() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_scalar").await;
+        let sql = "SELECT group_id FROM qx_test_scalar WHERE name = ?";
+        let present = mysql::exec_scalar_or(&mut conn, mysql::query_scalar::<i64>(sql).bind("b"), 0).await.unwrap();
+        let absent = mysql::exec_scalar_or(&mut conn, mysql::query_scalar::<i64>(sql).bind("z"), 0).await.unwrap();
+        _drop(&mut conn, "qx_test_scalar").await;
+        assert_eq!(present, 20);
+        assert_eq!(absent, 0);
+    });
+}
+
+#[test]
+fn decode_error_names_the_column() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        // the row type named on the helper, as callers of the baseline signatures do
+        let err = mysql::exec_one::<Dated>(&mut conn, mysql::query_as("SELECT 1 AS id, 'yesterday' AS created_at")).await.unwrap_err();
+        assert!(err.message().contains("failed to decode column 'created_at'"), "{}", err);
+    });
+}
+
+#[test]
+fn missing_column_names_the_struct() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_missing").await;
+        let res = mysql::exec_arr(&mut conn, mysql::query_as::<ItemWithPrice>("SELECT id, name FROM qx_test_missing")).await;
+        _drop(&mut conn, "qx_test_missing").await;
+        let err = res.unwrap_err();
+        assert!(err.message().contains("query result is missing column 'price' expected by struct ItemWithPrice"), "{}", err);
+    });
+}
+
+#[test]
+fn exec_exactly_one_tells_none_from_many() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_exactly").await;
+        let sql = "SELECT id, name FROM qx_test_exactly WHERE group_id = ?";
+        let none = mysql::exec_exactly_one(&mut conn, mysql::query_as::<Item>(sql).bind(30)).await;
+        let one = mysql::exec_exactly_one(&mut conn, mysql::query_as::<Item>(sql).bind(20)).await;
+        let many = mysql::exec_exactly_one(&mut conn, mysql::query_as::<Item>(sql).bind(10)).await;
+        _drop(&mut conn, "qx_test_exactly").await;
+        let none = none.unwrap_err();
+        assert_eq!(none.kind(), ErrorKind::NotFound);
+        assert!(none.message().contains("no row found"));
+        assert_eq!(one.unwrap().id, 2);
+        assert!(many.unwrap_err().message().contains("more than one row found"));
+    });
+}
+
+#[test]
+fn exec_opt_one_surfaces_decode_errors() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_opt").await;
+        let absent = mysql::exec_opt_one(&mut conn, mysql::query_as::<Item>("SELECT id, name FROM qx_test_opt WHERE id = 9")).await;
+        let malformed = mysql::exec_opt_one(&mut conn, mysql::query_as::<(i64, i64)>("SELECT id, name FROM qx_test_opt WHERE id = 1")).await;
+        _drop(&mut conn, "qx_test_opt").await;
+        assert!(absent.unwrap().is_none());
+        assert!(malformed.is_err());
+    });
+}
+
+#[test]
+fn exec_arr_capped_stops_past_the_cap() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        let sql = "WITH RECURSIVE seq (n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM seq WHERE n < 50) SELECT n FROM seq";
+        let err = mysql::exec_arr_capped(&mut conn, mysql::query_as::<(i64,)>(sql), 10).await.unwrap_err();
+        assert!(err.message().contains("result exceeded 10 rows"), "{}", err);
+        // the rest of the result was read away, the connection is usable
+        let rows = mysql::exec_arr_capped(&mut conn, mysql::query_as::<(i64,)>(sql), 50).await.unwrap();
+        assert_eq!(rows.len(), 50);
+    });
+}
+
+#[test]
+fn update_versioned_reports_lost_races() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE IF EXISTS qx_test_account")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("CREATE TEMPORARY TABLE qx_test_account (id BIGINT PRIMARY KEY, balance BIGINT NOT NULL, version BIGINT NOT NULL)")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("INSERT INTO qx_test_account VALUES (1, 100, 0)")).await.unwrap();
+        let sql = "UPDATE qx_test_account SET balance = ?, version = version + 1 WHERE id = ? AND version = ?";
+        let won = mysql::update_versioned(&mut conn, mysql::query(sql).bind(90).bind(1), 0).await.unwrap();
+        let lost = mysql::update_versioned(&mut conn, mysql::query(sql).bind(80).bind(1), 0).await.unwrap();
+        let balance = mysql::exec_scalar_or(&mut conn, mysql::query_scalar::<i64>("SELECT balance FROM qx_test_account WHERE id = 1"), 0).await.unwrap();
+        _drop(&mut conn, "qx_test_account").await;
+        assert!(won);
+        assert!(!lost);
+        assert_eq!(balance, 90);
+    });
+}
+
+#[test]
+fn exec_arr_max_time_aborts_on_the_server() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        // a cross join far too large to count in 100ms
+        let sql = "SELECT COUNT(*) FROM information_schema.columns a, information_schema.columns b, information_schema.columns c";
+        let err = mysql::exec_arr_max_time::<(i64,)>(&mut conn, sql, MySqlArguments::default(), 100).await.unwrap_err();
+        assert!(err.message().contains("ERROR 3024"), "{}", err);
+        let rows = mysql::exec_arr_max_time::<(i64,)>(&mut conn, "SELECT 1", MySqlArguments::default(), 1000).await.unwrap();
+        assert_eq!(rows, [(1,)]);
+        assert!(mysql::exec_arr_max_time::<(i64,)>(&mut conn, "DELETE FROM t", MySqlArguments::default(), 1000).await.is_err());
+    });
+}
+
+#[test]
+fn explain_returns_the_plan() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _items(&mut conn, "qx_test_explain").await;
+        let plan = mysql::explain(&mut conn, "SELECT * FROM qx_test_explain WHERE id = 1").await;
+        let version = mysql::server_version(&mut conn).await.unwrap();
+        let analyzed = match version.at_least(8, 0, 18) && !version.mariadb {
+            true => Some(mysql::explain_analyze(&mut conn, "SELECT * FROM qx_test_explain WHERE id = 1").await),
+            false => None,
+        };
+        _drop(&mut conn, "qx_test_explain").await;
+        assert!(!plan.unwrap().is_empty());
+        if let Some(analyzed) = analyzed {
+            assert!(!analyzed.unwrap().is_empty());
+        }
+    });
+}
+
+#[test]
+fn ensure_ddl_tolerates_existing_tables_and_indexes() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE IF EXISTS qx_test_ddl")).await.unwrap();
+        let create = "CREATE TEMPORARY TABLE qx_test_ddl (id BIGINT PRIMARY KEY, name VARCHAR(16) NOT NULL)";
+        mysql::ensure_ddl(&mut conn, create).await.unwrap();
+        mysql::ensure_ddl(&mut conn, create).await.unwrap();
+        let index = "CREATE INDEX qx_test_ddl_name ON qx_test_ddl (name)";
+        mysql::ensure_ddl(&mut conn, index).await.unwrap();
+        mysql::ensure_ddl(&mut conn, index).await.unwrap();
+        let other = mysql::ensure_ddl(&mut conn, "CREATE INDEX qx_test_ddl_missing ON qx_test_ddl (missing)").await;
+        _drop(&mut conn, "qx_test_ddl").await;
+        assert!(other.is_err());
+    });
+}
+
+#[test]
+fn fetch_in_splits_long_lists() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE IF EXISTS qx_test_in")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("CREATE TEMPORARY TABLE qx_test_in (id BIGINT PRIMARY KEY)")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("SET SESSION cte_max_recursion_depth = 30000")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("INSERT INTO qx_test_in WITH RECURSIVE seq (n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM seq WHERE n < 30000) SELECT n FROM seq")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("SET SESSION cte_max_recursion_depth = DEFAULT")).await.unwrap();
+        let ids: Vec<i64> = (1..=20000).map(|a| a * 3 / 2).collect();
+        let split: Vec<(i64,)> = mysql::fetch_in(&mut conn, "SELECT id FROM qx_test_in WHERE id in(?) ORDER BY id", &ids, 1000).await.unwrap();
+        let whole: Vec<(i64,)> = mysql::fetch_in(&mut conn, "SELECT id FROM qx_test_in WHERE id IN ( ? ) ORDER BY id", &ids, 0).await.unwrap();
+        let literal: Vec<(i64,)> = mysql::fetch_in(&mut conn, "SELECT id FROM qx_test_in WHERE id IN (?) AND '?' = '?' ORDER BY id", &[1i64, 2], 1000).await.unwrap();
+        _drop(&mut conn, "qx_test_in").await;
+        assert_eq!(split.iter().map(|a| a.0).collect::<Vec<_>>(), ids);
+        assert_eq!(whole, split);
+        assert_eq!(literal, [(1,), (2,)]);
+    });
+}
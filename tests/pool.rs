@@ -0,0 +1,127 @@
+// Every test sets up a database of its own, the pools of the others stay out of its way.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use qx_rs_server::env::DEFAULT;
+use qx_rs_server_sqlx::error::{self, ErrorKind};
+use qx_rs_server_sqlx::pool::{self, BeginFailure, IsolationLevel};
+use qx_rs_server_sqlx::{mysql, tx};
+
+#[test]
+fn connects_must_be_valid() {
+    common::run(|| async {
+        common::database_env("ZERO_CONNECTS", &[("MAX_CONNECTS", "0")]);
+        let err = pool::setup_database("ZERO_CONNECTS").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Config);
+        assert!(err.message().contains("MYSQL.ZERO_CONNECTS.MAX_CONNECTS must be at least 1, got 0"), "{}", err);
+        common::database_env("MIN_ABOVE_MAX", &[("MIN_CONNECTS", "3"), ("MAX_CONNECTS", "2")]);
+        let err = pool::setup_database("MIN_ABOVE_MAX").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Config);
+        assert!(err.message().contains("MYSQL.MIN_ABOVE_MAX.MIN_CONNECTS (3) must not exceed MYSQL.MIN_ABOVE_MAX.MAX_CONNECTS (2)"), "{}", err);
+    });
+}
+
+#[test]
+fn blob_above_max_allowed_packet_fails_before_sending() {
+    common::run(|| async {
+        common::database_env("SMALL_PACKET", &[("MAX_ALLOWED_PACKET", "1048576")]);
+        pool::setup_database("SMALL_PACKET").await.unwrap();
+        let blob = vec![0u8; 2 << 20];
+        let err = pool::check_packet_len("SMALL_PACKET", blob.len()).await.unwrap_err();
+        assert!(error::is_packet_too_large(&err));
+        assert!(err.message().contains("value of 2097152 bytes exceeds max_allowed_packet of 1048576 bytes"), "{}", err);
+        pool::check_packet_len("SMALL_PACKET", 1000).await.unwrap();
+    });
+}
+
+#[test]
+fn test_before_acquire_replaces_killed_connections() {
+    common::run(|| async {
+        common::database_env("KILLED", &[("MAX_CONNECTS", "1"), ("TEST_BEFORE_ACQUIRE", "true")]);
+        pool::setup_database("KILLED").await.unwrap();
+        let mut conn = pool::get_conn_from_database("KILLED").await.unwrap();
+        let killed = mysql::connection_id(&mut conn).await.unwrap();
+        drop(conn);
+        pool::kill_connection(DEFAULT, killed).await.unwrap();
+        let mut conn = pool::get_conn_from_database("KILLED").await.unwrap();
+        let id = mysql::connection_id(&mut conn).await.unwrap();
+        assert_ne!(id, killed);
+    });
+}
+
+#[test]
+fn try_begin_on_a_killed_connection_reports_it_lost() {
+    common::run(|| async {
+        common::database_env("BEGIN_LOST", &[("MAX_CONNECTS", "1")]);
+        pool::setup_database("BEGIN_LOST").await.unwrap();
+        let mut conn = pool::get_conn_from_database("BEGIN_LOST").await.unwrap();
+        let killed = mysql::connection_id(&mut conn).await.unwrap();
+        pool::kill_connection(DEFAULT, killed).await.unwrap();
+        match pool::try_begin(&mut conn).await {
+            Err((failure, _)) => assert_eq!(failure, BeginFailure::ConnectionLost),
+            Ok(_) => panic!("BEGIN on a killed connection succeeded"),
+        }
+        drop(conn);
+        // the broken connection is replaced, not handed out again
+        let mut conn = pool::get_conn_from_database("BEGIN_LOST").await.unwrap();
+        assert_ne!(mysql::connection_id(&mut conn).await.unwrap(), killed);
+        let trans = pool::try_begin(&mut conn).await.map_err(|(_, err)| err).unwrap();
+        trans.commit().await.unwrap();
+    });
+}
+
+#[test]
+fn isolation_level_applies_to_one_transaction() {
+    common::run(|| async {
+        common::database_env("ISOLATION", &[("MAX_CONNECTS", "1")]);
+        pool::setup_database("ISOLATION").await.unwrap();
+        let level = "SELECT @@SESSION.transaction_isolation";
+        let mut conn = pool::get_conn_from_database("ISOLATION").await.unwrap();
+        let id = mysql::connection_id(&mut conn).await.unwrap();
+        let before = mysql::exec_scalar_or(&mut conn, mysql::query_scalar::<String>(level), String::new()).await.unwrap();
+        let trans = pool::get_trans_with_isolation(&mut conn, IsolationLevel::Serializable).await.unwrap();
+        trans.rollback().await.unwrap();
+        drop(conn);
+        let mut conn = pool::get_conn_from_database("ISOLATION").await.unwrap();
+        assert_eq!(mysql::connection_id(&mut conn).await.unwrap(), id);
+        let mut trans = pool::get_trans(&mut conn).await.unwrap();
+        let after = mysql::exec_scalar_or(&mut trans, mysql::query_scalar::<String>(level), String::new()).await.unwrap();
+        trans.rollback().await.unwrap();
+        assert_eq!(after, before);
+        assert_ne!(after, "SERIALIZABLE");
+    });
+}
+
+#[test]
+fn acquire_retries_wait_out_a_spike() {
+    common::run(|| async {
+        common::database_env("SPIKE", &[("MAX_CONNECTS", "1"), ("ACQUIRE_RETRIES", "1")]);
+        pool::setup_database("SPIKE").await.unwrap();
+        let held = pool::get_conn_from_database("SPIKE").await.unwrap();
+        // the first acquire times out after the 30s acquire_timeout of sqlx, the retry gets it
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(35)).await;
+            drop(held);
+        });
+        let mut conn = pool::get_conn_from_database("SPIKE").await.unwrap();
+        mysql::exec(&mut conn, mysql::query("SELECT 1")).await.unwrap();
+        release.await.unwrap();
+    });
+}
+
+#[test]
+fn acquire_inside_a_transaction_on_an_exhausted_pool_fails_at_once() {
+    common::run(|| async {
+        common::database_env("HELD_TX", &[("MAX_CONNECTS", "1")]);
+        pool::setup_database("HELD_TX").await.unwrap();
+        let start = Instant::now();
+        let res = tx::run_in_transaction_retry("HELD_TX", 1, |_tx, _| Box::pin(async move {
+            pool::get_conn_from_database("HELD_TX").await.map(|_| ())
+        })).await;
+        let err = res.unwrap_err();
+        assert!(error::is_potential_self_deadlock(&err), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    });
+}
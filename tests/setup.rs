@@ -0,0 +1,29 @@
+// In a binary of its own, the server wide Connections counter only sees the setups of this test.
+
+mod common;
+
+use qx_rs_server_sqlx::{mysql, pool};
+
+const WHICH: &str = "CONCURRENT";
+
+#[test]
+fn concurrent_setups_connect_once() {
+    if common::test_url().is_none() {
+        eprintln!("MYSQL_TEST_URL is not set, skipped");
+        return;
+    }
+    common::database_env(WHICH, &[("MAX_CONNECTS", "2")]);
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        let count = "SELECT CAST(VARIABLE_VALUE AS UNSIGNED) FROM performance_schema.global_status WHERE VARIABLE_NAME = 'Connections'";
+        let before: u64 = mysql::exec_scalar_or(&mut conn, mysql::query_scalar(count), 0).await.unwrap();
+        let setups: Vec<_> = (0..50).map(|_| tokio::spawn(pool::setup_database(WHICH))).collect();
+        for setup in setups {
+            setup.await.unwrap().unwrap();
+        }
+        let after: u64 = mysql::exec_scalar_or(&mut conn, mysql::query_scalar(count), 0).await.unwrap();
+        // one pool of at most 2 connections, 50 pools would open 50 or more
+        assert!(after - before <= 2, "{} connections opened", after - before);
+        let _conn = pool::get_conn_from_database(WHICH).await.unwrap();
+    });
+}
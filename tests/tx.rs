@@ -0,0 +1,194 @@
+// The tests here lock rows across connections, so they use real tables, each its own, dropped
+// before creating and at the end.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sqlx::{MySql, MySqlConnection, QueryBuilder};
+use tokio::sync::oneshot;
+
+use qx_rs_server::env::DEFAULT;
+use qx_rs_server_sqlx::error::{self, DbError, ErrorKind};
+use qx_rs_server_sqlx::{mysql, pool, tx};
+
+async fn _table(conn: &mut MySqlConnection, table: &str, columns: &str) {
+    mysql::exec(&mut *conn, mysql::query(&format!("DROP TABLE IF EXISTS {}", table))).await.unwrap();
+    mysql::exec(&mut *conn, mysql::query(&format!("CREATE TABLE {} {}", table, columns))).await.unwrap();
+}
+
+async fn _drop(conn: &mut MySqlConnection, table: &str) {
+    mysql::exec(&mut *conn, mysql::query(&format!("DROP TABLE {}", table))).await.unwrap();
+}
+
+async fn _count(conn: &mut MySqlConnection, table: &str) -> i64 {
+    let sql = format!("SELECT COUNT(*) FROM {}", table);
+    mysql::exec_scalar_or(conn, mysql::query_scalar(&sql), -1).await.unwrap()
+}
+
+// on a connection of its own: changes rows 2 to 50, then waits for row 1
+async fn _rival(locked: oneshot::Sender<()>) -> error::Result<()> {
+    let mut conn = pool::get_conn().await?;
+    let mut trans = pool::get_trans(&mut conn).await?;
+    tx::tx_exec(&mut trans, mysql::query("UPDATE qx_test_deadlock SET n = n + 1 WHERE id >= 2")).await?;
+    let _ = locked.send(());
+    tx::tx_exec(&mut trans, mysql::query("UPDATE qx_test_deadlock SET n = n + 1 WHERE id = 1")).await?;
+    trans.commit().await
+}
+
+#[test]
+fn run_in_transaction_retry_reruns_a_deadlocked_transaction() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _table(&mut conn, "qx_test_deadlock", "(id BIGINT PRIMARY KEY, n BIGINT NOT NULL)").await;
+        let rows: Vec<String> = (1..=50).map(|id| format!("({}, 0)", id)).collect();
+        mysql::exec(&mut conn, mysql::query(&format!("INSERT INTO qx_test_deadlock VALUES {}", rows.join(", ")))).await.unwrap();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let rival = Arc::new(Mutex::new(None));
+        let res = tx::run_in_transaction_retry(DEFAULT, 3, |tx, attempt| {
+            let attempts = attempts.clone();
+            let rival = rival.clone();
+            Box::pin(async move {
+                attempts.lock().unwrap().push(attempt);
+                tx::tx_exec(tx, mysql::query("UPDATE qx_test_deadlock SET n = n + 1 WHERE id = 1")).await?;
+                if attempt == 1 {
+                    let (locked, ready) = oneshot::channel();
+                    *rival.lock().unwrap() = Some(tokio::spawn(_rival(locked)));
+                    ready.await.unwrap();
+                }
+                // waits for the rival, which waits for row 1: InnoDB rolls back the transaction
+                // that changed fewer rows, this one
+                tx::tx_exec(tx, mysql::query("UPDATE qx_test_deadlock SET n = n + 1 WHERE id = 2")).await?;
+                Ok(())
+            })
+        }).await;
+        let rival = rival.lock().unwrap().take().unwrap();
+        rival.await.unwrap().unwrap();
+        let n = mysql::exec_arr(&mut conn, mysql::query_as::<(i64,)>("SELECT n FROM qx_test_deadlock WHERE id <= 2 ORDER BY id")).await.unwrap();
+        _drop(&mut conn, "qx_test_deadlock").await;
+        res.unwrap();
+        assert_eq!(*attempts.lock().unwrap(), [1, 2]);
+        // the rival's and the second attempt's, none of the first attempt's
+        assert_eq!(n, [(2,), (2,)]);
+    });
+}
+
+#[test]
+fn a_cancelled_transaction_rolls_back_and_frees_its_connection() {
+    common::run(|| async {
+        common::database_env("CANCEL", &[("MAX_CONNECTS", "1")]);
+        pool::setup_database("CANCEL").await.unwrap();
+        let mut conn = pool::get_conn().await.unwrap();
+        _table(&mut conn, "qx_test_cancel", "(id BIGINT PRIMARY KEY)").await;
+        let cancelled = tokio::time::timeout(Duration::from_millis(500), async {
+            let mut conn = pool::get_conn_from_database("CANCEL").await.unwrap();
+            let mut trans = pool::get_trans(&mut conn).await.unwrap();
+            tx::tx_exec(&mut trans, mysql::query("INSERT INTO qx_test_cancel VALUES (1)")).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        }).await;
+        assert!(cancelled.is_err());
+        // the only connection of the pool, it would see its own insert were it still in the transaction
+        let mut cancel_conn = pool::get_conn_from_database("CANCEL").await.unwrap();
+        let seen = _count(&mut cancel_conn, "qx_test_cancel").await;
+        drop(cancel_conn);
+        let committed = _count(&mut conn, "qx_test_cancel").await;
+        _drop(&mut conn, "qx_test_cancel").await;
+        assert_eq!(seen, 0);
+        assert_eq!(committed, 0);
+    });
+}
+
+#[test]
+fn batch_commit_insert_commits_every_batch() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _table(&mut conn, "qx_test_batch", "(id BIGINT PRIMARY KEY)").await;
+        let rows: Vec<i64> = (1..=5000).collect();
+        let commits = Arc::new(AtomicUsize::new(0));
+        let inserted = tx::batch_commit_insert(DEFAULT, &rows, 1000, |tx, batch| {
+            let commits = commits.clone();
+            Box::pin(async move {
+                pool::on_commit(tx, move || {
+                    commits.fetch_add(1, Ordering::SeqCst);
+                });
+                let mut insert = QueryBuilder::<MySql>::new("INSERT INTO qx_test_batch (id) ");
+                insert.push_values(batch, |mut row, id| {
+                    row.push_bind(*id);
+                });
+                mysql::exec(tx, insert.build()).await.map(|(n, _)| n)
+            })
+        }).await.unwrap();
+        let count = _count(&mut conn, "qx_test_batch").await;
+        _drop(&mut conn, "qx_test_batch").await;
+        assert_eq!(inserted, 5000);
+        assert_eq!(count, 5000);
+        assert_eq!(commits.load(Ordering::SeqCst), 5);
+    });
+}
+
+#[test]
+fn share_locks_coexist_and_block_writers() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        _table(&mut conn, "qx_test_share", "(id BIGINT PRIMARY KEY, n BIGINT NOT NULL)").await;
+        mysql::exec(&mut conn, mysql::query("INSERT INTO qx_test_share VALUES (1, 0)")).await.unwrap();
+        let sql = "SELECT id, n FROM qx_test_share WHERE id = ?";
+        let mut a = pool::get_conn().await.unwrap();
+        let mut b = pool::get_conn().await.unwrap();
+        let mut trans_a = pool::get_trans(&mut a).await.unwrap();
+        let mut trans_b = pool::get_trans(&mut b).await.unwrap();
+        let rows_a = tx::select_for_share(&mut trans_a, mysql::query_as::<(i64, i64)>(sql).bind(1)).await.unwrap();
+        let rows_b = tx::select_for_share(&mut trans_b, mysql::query_as::<(i64, i64)>(sql).bind(1)).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("SET SESSION innodb_lock_wait_timeout = 1")).await.unwrap();
+        let blocked = mysql::exec(&mut conn, mysql::query("UPDATE qx_test_share SET n = 1 WHERE id = 1")).await;
+        mysql::exec(&mut conn, mysql::query("SET SESSION innodb_lock_wait_timeout = DEFAULT")).await.unwrap();
+        trans_a.rollback().await.unwrap();
+        trans_b.rollback().await.unwrap();
+        _drop(&mut conn, "qx_test_share").await;
+        assert_eq!(rows_a, [(1, 0)]);
+        assert_eq!(rows_b, [(1, 0)]);
+        let err = blocked.unwrap_err();
+        assert!(err.message().contains("ERROR 1205"), "{}", err);
+    });
+}
+
+#[test]
+fn commit_hooks_fire_on_commit_only() {
+    common::run(|| async {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let mut conn = pool::get_conn().await.unwrap();
+        let trans = pool::get_trans(&mut conn).await.unwrap();
+        let hook = fired.clone();
+        pool::on_commit(&trans, move || {
+            hook.fetch_add(1, Ordering::SeqCst);
+        });
+        trans.commit().await.unwrap();
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        let trans = pool::get_trans(&mut conn).await.unwrap();
+        let hook = fired.clone();
+        pool::on_commit(&trans, move || {
+            hook.fetch_add(10, Ordering::SeqCst);
+        });
+        trans.rollback().await.unwrap();
+        drop(conn);
+        // an error of `f` rolls back
+        let res = tx::run_in_transaction_retry(DEFAULT, 1, |tx, _| {
+            let hook = fired.clone();
+            pool::on_commit(tx, move || {
+                hook.fetch_add(100, Ordering::SeqCst);
+            });
+            Box::pin(async { Err::<(), _>(DbError::new(ErrorKind::Other, "rolled back")) })
+        }).await;
+        assert!(res.is_err());
+        tx::run_in_transaction_retry(DEFAULT, 1, |tx, _| {
+            let hook = fired.clone();
+            pool::on_commit(tx, move || {
+                hook.fetch_add(1000, Ordering::SeqCst);
+            });
+            Box::pin(async { Ok(()) })
+        }).await.unwrap();
+        assert_eq!(fired.load(Ordering::SeqCst), 1001);
+    });
+}
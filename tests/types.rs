@@ -0,0 +1,75 @@
+mod common;
+
+use qx_rs_server_sqlx::{mysql, pool, set};
+
+#[test]
+fn set_columns_round_trip() {
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE IF EXISTS qx_test_set")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("CREATE TEMPORARY TABLE qx_test_set (id BIGINT PRIMARY KEY, tags SET('news', 'tech', 'sport') NOT NULL)")).await.unwrap();
+        let tags = set::set_arg(&["tech", "news", "news"]).unwrap();
+        let none = set::set_arg::<&str>(&[]).unwrap();
+        mysql::exec(&mut conn, mysql::query("INSERT INTO qx_test_set VALUES (1, ?), (2, ?)").bind(tags).bind(none)).await.unwrap();
+        let rows = mysql::exec_arr(&mut conn, mysql::query_as::<(i64, set::SetValue)>("SELECT id, tags FROM qx_test_set ORDER BY id")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE qx_test_set")).await.unwrap();
+        // in the order of the definition, the duplicate stored once
+        assert_eq!(rows[0].1, set::SetValue(vec!["news".to_string(), "tech".to_string()]));
+        assert_eq!(rows[1].1, set::SetValue::default());
+    });
+}
+
+#[cfg(feature = "spatial")]
+#[test]
+fn points_round_trip() {
+    use qx_rs_server_sqlx::spatial;
+
+    common::run(|| async {
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE IF EXISTS qx_test_point")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("CREATE TEMPORARY TABLE qx_test_point (id BIGINT PRIMARY KEY, loc POINT NOT NULL)")).await.unwrap();
+        let sql = format!("INSERT INTO qx_test_point VALUES (1, {})", spatial::point_from_text_sql(0));
+        mysql::exec(&mut conn, mysql::query(&sql).bind(spatial::point_wkt(52.52, 13.405))).await.unwrap();
+        let (loc,): (spatial::Point,) = mysql::exec_one(&mut conn, mysql::query_as("SELECT ST_AsBinary(loc) FROM qx_test_point")).await.unwrap();
+        let (wkb,): (Vec<u8>,) = mysql::exec_one(&mut conn, mysql::query_as("SELECT ST_AsBinary(loc) FROM qx_test_point")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE qx_test_point")).await.unwrap();
+        assert_eq!(loc, spatial::Point { lat: 52.52, lng: 13.405 });
+        assert_eq!(spatial::decode_wkb_point(&wkb).unwrap(), (52.52, 13.405));
+    });
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_columns_round_trip_nested_values() {
+    use qx_rs_server_sqlx::json;
+    use serde::{Deserialize, Serialize};
+    use sqlx::types::Json;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Notify {
+        email: bool,
+        channels: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Prefs {
+        theme: String,
+        notify: Notify,
+    }
+
+    common::run(|| async {
+        let prefs = Prefs { theme: "dark".to_string(), notify: Notify { email: true, channels: vec!["push".to_string(), "sms".to_string()] } };
+        let mut conn = pool::get_conn().await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE IF EXISTS qx_test_json")).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("CREATE TEMPORARY TABLE qx_test_json (id BIGINT PRIMARY KEY, prefs JSON NULL)")).await.unwrap();
+        let sql = mysql::query("INSERT INTO qx_test_json VALUES (1, ?), (2, ?)");
+        mysql::exec(&mut conn, sql.bind(json::json_arg(&prefs).unwrap()).bind(json::json_arg(&None::<Prefs>).unwrap())).await.unwrap();
+        let rows = mysql::exec_arr(&mut conn, mysql::query_as::<(i64, Option<Json<Prefs>>)>("SELECT id, prefs FROM qx_test_json ORDER BY id")).await.unwrap();
+        // SQL NULL, not the JSON null literal
+        let nulls: i64 = mysql::exec_scalar_or(&mut conn, mysql::query_scalar("SELECT COUNT(*) FROM qx_test_json WHERE prefs IS NULL"), -1).await.unwrap();
+        mysql::exec(&mut conn, mysql::query("DROP TEMPORARY TABLE qx_test_json")).await.unwrap();
+        assert_eq!(rows[0].1.as_ref().map(|a| &a.0), Some(&prefs));
+        assert!(rows[1].1.is_none());
+        assert_eq!(nulls, 1);
+    });
+}